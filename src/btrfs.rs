@@ -0,0 +1,445 @@
+use std::io;
+use crate::{bytes::{le_u16, le_u32, le_u64}, Allocation, Volume};
+
+const SUPERBLOCK_OFFSET: u64 = 0x10000;
+const SUPERBLOCK_LEN: usize = 4096;
+const MAGIC: &[u8; 8] = b"_BHRfS_M";
+const SYS_CHUNK_ARRAY: usize = 0x32B;
+const SYS_CHUNK_ARRAY_MAX: usize = 2048;
+const CHUNK_ITEM_KEY: u8 = 228;
+const ROOT_ITEM_KEY: u8 = 132;
+const EXTENT_ITEM_KEY: u8 = 168;
+const METADATA_ITEM_KEY: u8 = 169;
+const EXTENT_TREE_OBJECTID: u64 = 2;
+// the root item of a tree: where its root is, and its level
+const ROOT_BYTENR: usize = 176;
+const ROOT_LEVEL: usize = 238;
+// data extents are at most 128 MiB, one that overlaps a range starts at most that much before it
+const MAX_EXTENT_LEN: u64 = 128 << 20;
+// tree blocks: a header, then items (leaves) or pointers to other blocks (nodes)
+const HEADER_LEN: usize = 0x65;
+const KEY_LEN: usize = 17;
+const ITEM_LEN: usize = KEY_LEN + 8;
+const KEY_PTR_LEN: usize = KEY_LEN + 16;
+const CHUNK_LEN: usize = 48;
+const STRIPE_LEN: usize = 32;
+// deeper than any real tree, a corrupted one could loop
+const MAX_LEVEL: u8 = 8;
+// block group profiles besides single and DUP (whose stripes are in the same device)
+const OTHER_PROFILES: [(u64, &str); 7] = [
+    (0x8, "RAID0"), (0x10, "RAID1"), (0x40, "RAID10"), (0x80, "RAID5"),
+    (0x100, "RAID6"), (0x200, "RAID1C3"), (0x400, "RAID1C4"),
+];
+
+// (objectid, type, offset), items are sorted by it
+type Key = (u64, u8, u64);
+
+fn key_at(b: &[u8]) -> Key {
+    (le_u64(b, 0), b[8], le_u64(b, 9))
+}
+
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid btrfs filesystem: {what}"))
+}
+
+// The primary superblock of the btrfs filesystem `read` reads from, None if it isn't one.
+fn superblock(read: impl Fn(&mut [u8], u64) -> io::Result<()>) -> io::Result<Option<Vec<u8>>> {
+    let mut sb = vec![0; SUPERBLOCK_LEN];
+    match read(&mut sb, SUPERBLOCK_OFFSET) {
+        // too small for btrfs
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    // it says where it is, the copies are at other offsets
+    Ok((&sb[0x40..0x48] == MAGIC && le_u64(&sb, 0x30) == SUPERBLOCK_OFFSET).then_some(sb))
+}
+
+/// UUID of the btrfs filesystem `read` reads from (its fsid, the one `blkid` shows). None if it
+/// isn't one.
+pub(crate) fn superblock_uuid(read: impl Fn(&mut [u8], u64) -> io::Result<()>) -> io::Result<Option<[u8; 16]>> {
+    Ok(superblock(read)?.map(|sb| sb[0x20..0x30].try_into().unwrap_or_default()))
+}
+
+// Part of the logical address space of the filesystem, and where it is in its devices.
+#[derive(Debug, Clone)]
+struct Chunk {
+    logical: u64,
+    len: u64,
+    kind: u64,
+    // (devid, offset in the device)
+    stripes: Vec<(u64, u64)>,
+}
+
+impl Chunk {
+    // a chunk item: the chunk, then its stripes
+    fn parse(logical: u64, item: &[u8]) -> io::Result<(Chunk, usize)> {
+        if item.len() < CHUNK_LEN {
+            return Err(invalid("truncated chunk item"));
+        }
+        let n = le_u16(item, 44) as usize;
+        let len = CHUNK_LEN + n * STRIPE_LEN;
+        if n == 0 || item.len() < len {
+            return Err(invalid("truncated chunk item"));
+        }
+        let stripes = item[CHUNK_LEN..len].chunks(STRIPE_LEN).map(|s| (le_u64(s, 0), le_u64(s, 8))).collect();
+        Ok((Chunk { logical, len: le_u64(item, 0), kind: le_u64(item, 24), stripes }, len))
+    }
+}
+
+/// Where the logical addresses of a btrfs filesystem (those FIEMAP gives) are in one of its
+/// devices, from its chunk tree.
+pub(crate) struct ChunkMap {
+    // the device read, the one with its superblock
+    devid: u64,
+    // by logical address
+    chunks: Vec<Chunk>,
+}
+
+impl ChunkMap {
+    /// Reads the chunk tree of the btrfs filesystem `read` reads from (at device offsets). None
+    /// if it isn't btrfs.
+    pub(crate) fn read(read: impl Fn(&mut [u8], u64) -> io::Result<()>) -> io::Result<Option<ChunkMap>> {
+        let Some(sb) = superblock(&read)? else {
+            return Ok(None);
+        };
+        let devid = le_u64(&sb, 0xC9);
+        // the chunks of the chunk tree itself are in the superblock
+        let array_len = le_u32(&sb, 0xA0) as usize;
+        if array_len > SYS_CHUNK_ARRAY_MAX {
+            return Err(invalid("system chunk array too long"));
+        }
+        let mut array = &sb[SYS_CHUNK_ARRAY..SYS_CHUNK_ARRAY + array_len];
+        let mut system = vec![];
+        while !array.is_empty() {
+            if array.len() < KEY_LEN || array[8] != CHUNK_ITEM_KEY {
+                return Err(invalid("unexpected item in the system chunk array"));
+            }
+            let (chunk, len) = Chunk::parse(le_u64(array, 9), &array[KEY_LEN..])?;
+            system.push(chunk);
+            array = &array[KEY_LEN + len..];
+        }
+        let bootstrap = ChunkMap::new(devid, system);
+        let node_size = le_u32(&sb, 0x94) as usize;
+        if node_size < HEADER_LEN {
+            return Err(invalid("node size too small"));
+        }
+        let mut chunks = vec![];
+        let read_logical = |buf: &mut [u8], pos| bootstrap.read_exact_at(&read, buf, pos);
+        tree_items(read_logical, node_size, (le_u64(&sb, 0x58), sb[0xC7]), (0, CHUNK_ITEM_KEY, 0), (u64::MAX, CHUNK_ITEM_KEY, u64::MAX), &mut |key, data| {
+            if key.1 == CHUNK_ITEM_KEY {
+                chunks.push(Chunk::parse(key.2, data)?.0);
+            }
+            Ok(())
+        })?;
+        Ok(Some(ChunkMap::new(devid, chunks)))
+    }
+
+    fn new(devid: u64, mut chunks: Vec<Chunk>) -> ChunkMap {
+        chunks.sort_by_key(|c| c.logical);
+        ChunkMap { devid, chunks }
+    }
+
+    /// Where the logical address `pos` is in the device: at which offset, and how many bytes
+    /// after it follow it there. Only single and DUP chunks can be read, the data of the others
+    /// is spread over several devices (or has parity).
+    fn locate(&self, pos: u64) -> io::Result<(u64, u64)> {
+        let i = self.chunks.partition_point(|c| c.logical <= pos);
+        let chunk = i.checked_sub(1).map(|i| &self.chunks[i]).filter(|c| pos < c.logical + c.len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Byte {pos} of the btrfs filesystem isn't in any of its chunks")))?;
+        if let Some((_, name)) = OTHER_PROFILES.iter().find(|(flag, _)| chunk.kind & flag != 0) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                format!("Byte {pos} of the btrfs filesystem is in a {name} chunk, only single and DUP ones can be read")));
+        }
+        // DUP has two copies in the device, either will do
+        let (_, offset) = chunk.stripes.iter().find(|(devid, _)| *devid == self.devid)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+                format!("Byte {pos} of the btrfs filesystem is in another of its devices (devid {}), not this one", chunk.stripes[0].0)))?;
+        let relative = pos - chunk.logical;
+        Ok((offset + relative, chunk.len - relative))
+    }
+
+    /// Reads `buf.len()` bytes at the logical address `pos`, through `read` at device offsets.
+    pub(crate) fn read_exact_at(&self, read: impl Fn(&mut [u8], u64) -> io::Result<()>, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            let (at, len) = self.locate(pos)?;
+            let n = u64::min(len, buf.len() as u64) as usize;
+            read(&mut buf[..n], at)?;
+            buf = &mut buf[n..];
+            pos += n as u64;
+        }
+        Ok(())
+    }
+}
+
+// Calls `visit` with the key and the data of the items with keys in `min..=max` of the tree whose
+// root is at (logical address, level), read by `read` at logical addresses.
+fn tree_items(
+    read: impl Fn(&mut [u8], u64) -> io::Result<()>,
+    node_size: usize,
+    root: (u64, u8),
+    min: Key,
+    max: Key,
+    visit: &mut impl FnMut(Key, &[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut pending = vec![root];
+    while let Some((logical, level)) = pending.pop() {
+        let mut node = vec![0; node_size];
+        read(&mut node, logical)?;
+        if le_u64(&node, 0x30) != logical || node[0x64] != level || level > MAX_LEVEL {
+            return Err(invalid(&format!("tree block at {logical} isn't one")));
+        }
+        let count = le_u32(&node, 0x60) as usize;
+        if level == 0 {
+            for i in 0..count {
+                let item = node.get(HEADER_LEN + i * ITEM_LEN..HEADER_LEN + (i + 1) * ITEM_LEN)
+                    .ok_or_else(|| invalid("too many items in a tree leaf"))?;
+                let key = key_at(item);
+                if key < min || key > max {
+                    continue;
+                }
+                let start = HEADER_LEN + le_u32(item, KEY_LEN) as usize;
+                let data = node.get(start..start + le_u32(item, KEY_LEN + 4) as usize)
+                    .ok_or_else(|| invalid("item outside of its leaf"))?;
+                visit(key, data)?;
+            }
+            continue;
+        }
+        let ptrs = (0..count)
+            .map(|i| node.get(HEADER_LEN + i * KEY_PTR_LEN..HEADER_LEN + (i + 1) * KEY_PTR_LEN)
+                .map(|ptr| (key_at(ptr), le_u64(ptr, KEY_LEN)))
+                .ok_or_else(|| invalid("too many pointers in a tree node")))
+            .collect::<io::Result<Vec<_>>>()?;
+        // a child has the keys from its own up to the next child's
+        for (i, (key, child)) in ptrs.iter().enumerate() {
+            if *key <= max && ptrs.get(i + 1).is_none_or(|(next, _)| *next > min) {
+                pending.push((*child, level - 1));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A btrfs filesystem, read through its volume, to look up blocks in its extent tree.
+pub struct Btrfs {
+    volume: Volume,
+    sector_size: u64,
+    node_size: usize,
+    uuid: [u8; 16],
+    // (logical address, level) of the root of the extent tree
+    extent_root: (u64, u8),
+}
+
+impl Btrfs {
+    /// Reads the superblock of `volume` and finds its extent tree, fails if it isn't btrfs.
+    pub fn open(volume: Volume) -> io::Result<Self> {
+        let sb = superblock(|buf, pos| volume.read_device(buf, pos))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Not a btrfs filesystem"))?;
+        let (sector_size, node_size) = (le_u32(&sb, 0x90) as u64, le_u32(&sb, 0x94) as usize);
+        if !(512..=65536).contains(&sector_size) || !sector_size.is_power_of_two() || node_size < HEADER_LEN {
+            return Err(invalid("block sizes"));
+        }
+        let mut extent_root = None;
+        let extent_tree = (EXTENT_TREE_OBJECTID, ROOT_ITEM_KEY, 0);
+        let read = |buf: &mut [u8], pos| volume.read_exact_at(buf, pos);
+        tree_items(read, node_size, (le_u64(&sb, 0x50), sb[0xC6]), extent_tree, (extent_tree.0, extent_tree.1, u64::MAX), &mut |_, item| {
+            if item.len() <= ROOT_LEVEL {
+                return Err(invalid("truncated root item"));
+            }
+            extent_root = Some((le_u64(item, ROOT_BYTENR), item[ROOT_LEVEL]));
+            Ok(())
+        })?;
+        let extent_root = extent_root.ok_or_else(|| invalid("no extent tree"))?;
+        Ok(Btrfs { volume, sector_size, node_size, uuid: sb[0x20..0x30].try_into().unwrap_or_default(), extent_root })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    /// Whether the blocks `first..last` (sectors of the logical address space) are in an extent
+    /// now, of data or metadata.
+    pub(crate) fn allocation(&mut self, first: u64, last: u64) -> io::Result<Allocation> {
+        let (start, end) = (first * self.sector_size, last * self.sector_size);
+        let read = |buf: &mut [u8], pos| self.volume.read_exact_at(buf, pos);
+        let mut allocated = 0;
+        let (min, max) = ((start.saturating_sub(MAX_EXTENT_LEN), 0, 0), (end - 1, u8::MAX, u64::MAX));
+        tree_items(read, self.node_size, self.extent_root, min, max, &mut |(bytenr, kind, offset), _| {
+            let len = match kind {
+                EXTENT_ITEM_KEY => offset,
+                METADATA_ITEM_KEY => self.node_size as u64,
+                _ => return Ok(()),
+            };
+            allocated += u64::min(end, bytenr.saturating_add(len)).saturating_sub(u64::max(start, bytenr));
+            Ok(())
+        })?;
+        Ok(match allocated {
+            0 => Allocation::Free,
+            n if n == end - start => Allocation::Allocated,
+            _ => Allocation::Partial,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use super::*;
+
+    const NODE: usize = 4096;
+    const DUP: u64 = 0x20;
+    const RAID1: u64 = 0x10;
+
+    fn read_in(disk: &[u8]) -> impl Fn(&mut [u8], u64) -> io::Result<()> + '_ {
+        |buf, pos| {
+            let bytes = disk.get(pos as usize..pos as usize + buf.len()).ok_or(io::ErrorKind::UnexpectedEof)?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    fn key(b: &mut [u8], (objectid, kind, offset): Key) {
+        b[..8].copy_from_slice(&objectid.to_le_bytes());
+        b[8] = kind;
+        b[9..17].copy_from_slice(&offset.to_le_bytes());
+    }
+
+    // a chunk item of `len` bytes with these (devid, offset) stripes
+    fn chunk(len: u64, kind: u64, stripes: &[(u64, u64)]) -> Vec<u8> {
+        let mut item = vec![0; CHUNK_LEN + stripes.len() * STRIPE_LEN];
+        item[..8].copy_from_slice(&len.to_le_bytes());
+        item[24..32].copy_from_slice(&kind.to_le_bytes());
+        item[44..46].copy_from_slice(&(stripes.len() as u16).to_le_bytes());
+        for (i, (devid, offset)) in stripes.iter().enumerate() {
+            let stripe = &mut item[CHUNK_LEN + i * STRIPE_LEN..];
+            stripe[..8].copy_from_slice(&devid.to_le_bytes());
+            stripe[8..16].copy_from_slice(&offset.to_le_bytes());
+        }
+        item
+    }
+
+    fn header(block: &mut [u8], logical: u64, level: u8, count: usize) {
+        block[0x30..0x38].copy_from_slice(&logical.to_le_bytes());
+        block[0x60..0x64].copy_from_slice(&(count as u32).to_le_bytes());
+        block[0x64] = level;
+    }
+
+    // a leaf at `logical` with these items, their data from the end of it
+    fn leaf(block: &mut [u8], logical: u64, items: &[(Key, Vec<u8>)]) {
+        header(block, logical, 0, items.len());
+        let mut end = NODE - HEADER_LEN;
+        for (i, (k, data)) in items.iter().enumerate() {
+            end -= data.len();
+            let item = &mut block[HEADER_LEN + i * ITEM_LEN..];
+            key(item, *k);
+            item[KEY_LEN..KEY_LEN + 4].copy_from_slice(&(end as u32).to_le_bytes());
+            item[KEY_LEN + 4..KEY_LEN + 8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            block[HEADER_LEN + end..][..data.len()].copy_from_slice(data);
+        }
+    }
+
+    // a node at `logical` with these (key, child) pointers
+    fn node(block: &mut [u8], logical: u64, level: u8, ptrs: &[(Key, u64)]) {
+        header(block, logical, level, ptrs.len());
+        for (i, (k, child)) in ptrs.iter().enumerate() {
+            let ptr = &mut block[HEADER_LEN + i * KEY_PTR_LEN..];
+            key(ptr, *k);
+            ptr[KEY_LEN..KEY_LEN + 8].copy_from_slice(&child.to_le_bytes());
+        }
+    }
+
+    // device 1 of a filesystem whose chunk tree is in the system chunk at 1 MiB, at 128 KiB of
+    // the device
+    fn device() -> Vec<u8> {
+        let mut disk = vec![0; 0x50000];
+        let sb = &mut disk[SUPERBLOCK_OFFSET as usize..];
+        sb[0x30..0x38].copy_from_slice(&SUPERBLOCK_OFFSET.to_le_bytes());
+        sb[0x40..0x48].copy_from_slice(MAGIC);
+        sb[0x58..0x60].copy_from_slice(&0x100000u64.to_le_bytes());
+        sb[0x94..0x98].copy_from_slice(&(NODE as u32).to_le_bytes());
+        sb[0xC9..0xD1].copy_from_slice(&1u64.to_le_bytes());
+        let system = chunk(0x10000, 0x2, &[(1, 0x20000)]);
+        let array = &mut sb[SYS_CHUNK_ARRAY..];
+        key(array, (256, CHUNK_ITEM_KEY, 0x100000));
+        array[KEY_LEN..KEY_LEN + system.len()].copy_from_slice(&system);
+        let array_len = (KEY_LEN + system.len()) as u32;
+        sb[0xA0..0xA4].copy_from_slice(&array_len.to_le_bytes());
+        leaf(&mut disk[0x20000..], 0x100000, &[
+            ((256, CHUNK_ITEM_KEY, 0x100000), system),
+            ((256, CHUNK_ITEM_KEY, 0x200000), chunk(0x10000, 0x1 | DUP, &[(1, 0x30000), (1, 0x40000)])),
+            ((256, CHUNK_ITEM_KEY, 0x300000), chunk(0x10000, 0x1 | RAID1, &[(1, 0x30000), (2, 0x30000)])),
+            ((256, CHUNK_ITEM_KEY, 0x400000), chunk(0x10000, 0x1, &[(2, 0)])),
+        ]);
+        disk
+    }
+
+    #[test]
+    fn chunk_tree() {
+        let mut disk = device();
+        disk[0x30010..0x30014].copy_from_slice(b"data");
+        let map = ChunkMap::read(read_in(&disk)).unwrap().unwrap();
+        assert_eq!(map.chunks.iter().map(|c| c.logical).collect::<Vec<_>>(), [0x100000, 0x200000, 0x300000, 0x400000]);
+        assert_eq!(map.locate(0x100000).unwrap(), (0x20000, 0x10000));
+        // the first copy of DUP
+        assert_eq!(map.locate(0x200010).unwrap(), (0x30010, 0xFFF0));
+        let mut buf = [0; 4];
+        map.read_exact_at(read_in(&disk), &mut buf, 0x200010).unwrap();
+        assert_eq!(&buf, b"data");
+        assert_eq!(map.locate(0x300000).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert_eq!(map.locate(0x400000).unwrap_err().kind(), io::ErrorKind::NotFound);
+        for pos in [0xFFFFF, 0x210000, 0x500000] {
+            assert_eq!(map.locate(pos).unwrap_err().kind(), io::ErrorKind::InvalidData, "{pos:#x}");
+        }
+    }
+
+    #[test]
+    fn not_btrfs() {
+        assert!(ChunkMap::read(read_in(&[0; 0x20000])).unwrap().is_none());
+        assert!(ChunkMap::read(read_in(&[0; 4096])).unwrap().is_none());
+        let mut disk = device();
+        disk[SUPERBLOCK_OFFSET as usize + 0xA0..][..4].copy_from_slice(&4096u32.to_le_bytes());
+        assert_eq!(ChunkMap::read(read_in(&disk)).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn tree_children() {
+        // a node at 16 KiB over three leaves, at logical addresses
+        let mut disk = vec![0; 5 * NODE];
+        let items = |objectids: &[u64]| objectids.iter().map(|&o| ((o, EXTENT_ITEM_KEY, 0), o.to_le_bytes().to_vec())).collect::<Vec<_>>();
+        leaf(&mut disk[NODE..], NODE as u64, &items(&[10, 12, 15]));
+        leaf(&mut disk[2 * NODE..], 2 * NODE as u64, &items(&[20, 25]));
+        leaf(&mut disk[3 * NODE..], 3 * NODE as u64, &items(&[30, 35]));
+        node(&mut disk[4 * NODE..], 4 * NODE as u64, 1, &[
+            ((10, 0, 0), NODE as u64),
+            ((20, 0, 0), 2 * NODE as u64),
+            ((30, 0, 0), 3 * NODE as u64),
+        ]);
+        // the objectids of the items found, and the blocks read
+        let search = |min: u64, max: u64| {
+            let read = RefCell::new(vec![]);
+            let mut found = vec![];
+            let read_logged = |buf: &mut [u8], pos| {
+                read.borrow_mut().push(pos / NODE as u64);
+                read_in(&disk)(buf, pos)
+            };
+            tree_items(read_logged, NODE, (4 * NODE as u64, 1), (min, 0, 0), (max, u8::MAX, u64::MAX), &mut |key, data| {
+                assert_eq!(le_u64(data, 0), key.0);
+                found.push(key.0);
+                Ok(())
+            }).unwrap();
+            let mut read = read.into_inner();
+            found.sort_unstable();
+            read.sort_unstable();
+            (found, read)
+        };
+        assert_eq!(search(12, 25), (vec![12, 15, 20, 25], vec![1, 2, 4]));
+        // the first leaf has keys up to the second one's, not its own
+        assert_eq!(search(20, 22), (vec![20], vec![2, 4]));
+        assert_eq!(search(16, 19), (vec![], vec![1, 4]));
+        assert_eq!(search(31, u64::MAX), (vec![35], vec![3, 4]));
+        assert_eq!(search(0, 9), (vec![], vec![4]));
+    }
+}
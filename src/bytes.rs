@@ -0,0 +1,25 @@
+// Integers in on-disk structures, at byte `at` of `b`. Zero if `b` is too short for them.
+
+pub(crate) fn le_u16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(b.get(at..at + 2).and_then(|b| b.try_into().ok()).unwrap_or_default())
+}
+
+pub(crate) fn le_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b.get(at..at + 4).and_then(|b| b.try_into().ok()).unwrap_or_default())
+}
+
+pub(crate) fn le_u64(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b.get(at..at + 8).and_then(|b| b.try_into().ok()).unwrap_or_default())
+}
+
+pub(crate) fn be_u16(b: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes(b.get(at..at + 2).and_then(|b| b.try_into().ok()).unwrap_or_default())
+}
+
+pub(crate) fn be_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(b.get(at..at + 4).and_then(|b| b.try_into().ok()).unwrap_or_default())
+}
+
+pub(crate) fn be_u64(b: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(b.get(at..at + 8).and_then(|b| b.try_into().ok()).unwrap_or_default())
+}
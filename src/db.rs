@@ -0,0 +1,757 @@
+use std::{collections::HashMap, env, fs::{self, create_dir_all, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, os::fd::AsRawFd, path::{Path, PathBuf}, thread, time::{Duration, Instant}};
+use crate::{Extent, ExtentFlags, FileKind, ZombieFile};
+
+const DB: &str = "DB.bin";
+const DB_ENV: &str = "RMLS_DB";
+// How long to wait for another rmls to release the database before giving up.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Layout of the database:
+// | MAGIC (8 bytes) | VERSION (u32 LE) | record | record | ...
+// and every record:
+// | RECORD_MAGIC (4 bytes) | LEN (u32 LE) | CRC32 of LEN and PAYLOAD (u32 LE) | PAYLOAD (LEN bytes) |
+// The payload is a `ZombieFile` encoded as a MessagePack map, so the field names are stored with
+// the values. New fields can be added to `ZombieFile` (with `#[serde(default)]`) without breaking
+// old databases, VERSION only has to change when the layout itself changes.
+// RECORD_MAGIC lets the reader find the next record after a damaged one.
+const MAGIC: [u8; 8] = *b"RMLS-DB\0";
+const VERSION: u32 = 2;
+const HEADER_LEN: usize = MAGIC.len() + 4;
+const RECORD_MAGIC: [u8; 4] = *b"ZREC";
+const RECORD_HEADER_LEN: usize = RECORD_MAGIC.len() + 4 + 4;
+
+/// Location of the deletion database. In order of preference: the path given with `--db`,
+/// `$RMLS_DB`, `$XDG_DATA_HOME/rmls/DB.bin` and `~/.local/share/rmls/DB.bin`.
+/// It doesn't depend on the current directory, so a file deleted from `~/a` can be recovered from `~/b`.
+pub fn db_path(flag: Option<PathBuf>) -> PathBuf {
+    if let Some(path) = flag {
+        return path;
+    }
+    let non_empty = |var| env::var_os(var).filter(|v| !v.is_empty());
+    if let Some(path) = non_empty(DB_ENV) {
+        return path.into();
+    }
+    // the spec says relative paths in $XDG_DATA_HOME are invalid and should be ignored
+    let data_home = non_empty("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .unwrap_or_else(|| {
+            let home = non_empty("HOME").expect("Neither $XDG_DATA_HOME nor $HOME are set, use --db <path>");
+            Path::new(&home).join(".local").join("share")
+        });
+    data_home.join("rmls").join(DB)
+}
+
+/// Every file recorded in the database. Damaged records are skipped and reported on stderr.
+/// Databases written by older versions are upgraded in place (the original is kept next to it
+/// as `<db>.v<version>`).
+pub fn files_from_db<D: AsRef<Path>>(db: D) -> io::Result<Vec<ZombieFile>> {
+    let db = db.as_ref();
+    let lock = Lock::shared(db)?;
+    let buff = read_db(db)?;
+    let (records, damage) = match version(&buff, db)? {
+        Some(VERSION) => read_records(&buff),
+        _ if buff.is_empty() => return Ok(vec![]),
+        _ => {
+            drop(lock);
+            let _lock = Lock::exclusive(db)?;
+            // someone else could have migrated it while we weren't holding the lock
+            let buff = read_db(db)?;
+            return match version(&buff, db)? {
+                Some(VERSION) => Ok(read_records(&buff).0.into_iter().map(|(_, file)| file).collect()),
+                old => migrate(db, &buff, old.unwrap_or(0)),
+            };
+        }
+    };
+    for damage in &damage {
+        eprintln!("Warning: {:?}: {damage}", db);
+    }
+    Ok(records.into_iter().map(|(_, file)| file).collect())
+}
+
+/// The database open for appending records. No other rmls can read or write it until it's dropped.
+pub(crate) struct DbWriter {
+    file: File,
+    _lock: Lock,
+}
+
+/// Opens the database for appending records, writing the header if it's a new one.
+pub(crate) fn open_for_append(db: &Path) -> io::Result<DbWriter> {
+    let lock = Lock::exclusive(db)?;
+    let mut file = OpenOptions::new()
+        .create(true).read(true).append(true)
+        .open(db)?;
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    (&mut file).take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    if header.is_empty() {
+        file.write_all(&header_bytes())?;
+        file.sync_all()?;
+        sync_dir(db)?;
+        return Ok(DbWriter { file, _lock: lock });
+    }
+    match version(&header, db)? {
+        Some(VERSION) => Ok(DbWriter { file, _lock: lock }),
+        // old database, we can't mix records of different versions
+        old => {
+            let mut buff = vec![];
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut buff)?;
+            migrate(db, &buff, old.unwrap_or(0))?;
+            // the migrated database is a new file, the one we have open is the backup now
+            let file = OpenOptions::new().read(true).append(true).open(db)?;
+            Ok(DbWriter { file, _lock: lock })
+        }
+    }
+}
+
+impl DbWriter {
+    /// Appends one record and waits until it reaches the disk, so a crash right after can't lose it.
+    pub(crate) fn write_zombie_file(&mut self, file: &ZombieFile) -> io::Result<()> {
+        let mut data = vec![];
+        frame_into(&mut data, file)?;
+        self.file.write_all(&data)?;
+        self.file.sync_data()
+    }
+}
+
+// Advisory lock (flock) on `<db>.lock`, released when dropped. Readers share it, writers have
+// it for themselves. It's a separate file because migrations replace the database with a new one.
+// Readers that can't create it lock the database itself, they can't migrate it anyway.
+struct Lock(File);
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // closing the file would release it too, this just doesn't depend on it
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+impl Lock {
+    fn shared(db: &Path) -> io::Result<Self> {
+        Self::new(db, libc::LOCK_SH)
+    }
+
+    fn exclusive(db: &Path) -> io::Result<Self> {
+        Self::new(db, libc::LOCK_EX)
+    }
+
+    fn new(db: &Path, operation: libc::c_int) -> io::Result<Self> {
+        let path = with_suffix(db, ".lock");
+        let create = || {
+            if let Some(dir) = db.parent() {
+                create_dir_all(dir)?;
+            }
+            OpenOptions::new().create(true).write(true).truncate(false).open(&path)
+        };
+        let file = match create() {
+            // we can still read a database we can't write (someone else's, on a read-only mount...):
+            // with its lock file if there's one, the database itself otherwise
+            Err(_) if operation == libc::LOCK_SH => File::open(&path).or_else(|_| File::open(db))
+                .map_err(|e| io::Error::new(e.kind(), format!("No database at {:?}: {e}", db)))?,
+            file => file?,
+        };
+        let start = Instant::now();
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+                return Ok(Lock(file));
+            }
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::Interrupted => {}
+                io::ErrorKind::WouldBlock if start.elapsed() < LOCK_TIMEOUT => thread::sleep(Duration::from_millis(50)),
+                io::ErrorKind::WouldBlock => {
+                    let msg = format!("Database {:?} is busy: another rmls has been using it for more than {}s", db, LOCK_TIMEOUT.as_secs());
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, msg));
+                }
+                _ => return Err(err),
+            }
+        }
+    }
+}
+
+/// Result of checking the database with [`fsck`].
+#[derive(Debug, Clone, Default)]
+pub struct Fsck {
+    /// Format version of the checked database.
+    pub version: u32,
+    /// Records that could be read.
+    pub records: usize,
+    /// Records written to the repaired copy.
+    pub kept: usize,
+    pub problems: Vec<Problem>,
+}
+
+/// Something wrong found by [`fsck`]. Offsets are in bytes from the start of the database.
+#[derive(Debug, Clone)]
+pub enum Problem {
+    Damaged(Damage),
+    /// Same record as the one at `first`. Only the first one is kept.
+    Duplicate { offset: usize, first: usize, name: String },
+    /// The extents can't be right. The record is kept, but recovering it will probably give garbage.
+    BadExtents { offset: usize, name: String, reason: String },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Damaged(damage) => write!(f, "{damage}"),
+            Problem::Duplicate { offset, first, name } => write!(f, "duplicate record at byte {offset} ({name}), same as the one at byte {first}"),
+            Problem::BadExtents { offset, name, reason } => write!(f, "record at byte {offset} ({name}): {reason}"),
+        }
+    }
+}
+
+/// Checks every record of the database and writes the ones that could be read to `output`,
+/// without the damaged bytes and the duplicates, in the current format. The database itself is not modified.
+pub fn fsck<D: AsRef<Path>, O: AsRef<Path>>(db: D, output: O) -> io::Result<Fsck> {
+    let db = db.as_ref();
+    let _lock = Lock::shared(db)?;
+    let buff = read_db(db)?;
+    let mut report = Fsck { version: VERSION, ..Default::default() };
+    let records = match version(&buff, db)? {
+        Some(VERSION) => {
+            let (records, damage) = read_records(&buff);
+            report.problems.extend(damage.into_iter().map(Problem::Damaged));
+            records
+        }
+        _ if buff.is_empty() => vec![],
+        // older formats have no checksums, nothing after the first damaged record can be read
+        old => {
+            report.version = old.unwrap_or(0);
+            let (records, damage) = read_legacy(&buff, report.version);
+            report.problems.extend(damage.map(Problem::Damaged));
+            records
+        }
+    };
+    report.records = records.len();
+
+    let mut kept: Vec<(usize, ZombieFile)> = vec![];
+    // offset of the first record with every content, by its hash
+    let mut seen: HashMap<blake3::Hash, usize> = HashMap::new();
+    for (offset, file) in records {
+        let hash = record_hash(&file)?;
+        if let Some(&first) = seen.get(&hash) {
+            report.problems.push(Problem::Duplicate { offset, first, name: file.name.clone() });
+            continue;
+        }
+        seen.insert(hash, offset);
+        if let Some(reason) = extents_problem(&file).filter(|_| file.kind == FileKind::File && file.stash.is_none()) {
+            report.problems.push(Problem::BadExtents { offset, name: file.name.clone(), reason });
+        }
+        kept.push((offset, file));
+    }
+    report.kept = kept.len();
+    let kept: Vec<_> = kept.into_iter().map(|(_, file)| file).collect();
+    replace(output.as_ref(), &kept)?;
+    Ok(report)
+}
+
+fn record_hash(file: &ZombieFile) -> io::Result<blake3::Hash> {
+    let payload = rmp_serde::to_vec_named(file)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(blake3::hash(&payload))
+}
+
+// Why the extents of `file` can't describe its content, if they can't.
+fn extents_problem(file: &ZombieFile) -> Option<String> {
+    if let Some(i) = file.extents.iter().position(|e| e.len == 0) {
+        return Some(format!("extent {i} has length 0"));
+    }
+    // only extents packed with other data can start in the middle of a block
+    let aligned = |e: &Extent| !e.readable() || e.flags().contains(ExtentFlags::NOT_ALIGNED) || e.start.is_multiple_of(file.block_size());
+    if let Some(i) = file.extents.iter().position(|e| file.block_size > 0 && !aligned(e)) {
+        return Some(format!("extent {i} doesn't start at a block of {} bytes", file.block_size));
+    }
+    // sparse files have less bytes in extents than their length, but we only know which ones are when we have the offsets
+    let covered: u64 = file.extents.iter().map(|e| e.len).sum();
+    if covered < file.len as u64 && file.extents.iter().all(|e| e.logical.is_none()) {
+        return Some(format!("extents cover {covered} bytes but the file has {}", file.len));
+    }
+    let overlap = |key: fn(&Extent) -> Option<u64>| {
+        let mut ranges: Vec<_> = file.extents.iter().filter_map(|e| Some((key(e)?, e.len))).collect();
+        ranges.sort();
+        ranges.windows(2).any(|w| w[0].0 + w[0].1 > w[1].0)
+    };
+    // extents without an address on disk say they're at 0
+    if overlap(|e| e.readable().then_some(e.start)) {
+        return Some("extents overlap".to_owned());
+    }
+    if overlap(|e| e.logical) {
+        return Some("extents overlap in the file".to_owned());
+    }
+    None
+}
+
+/// Bytes of the database that couldn't be read as records.
+#[derive(Debug, Clone, Copy)]
+pub enum Damage {
+    /// The database ends in the middle of a record, a write interrupted by a crash.
+    Truncated { offset: usize, len: usize },
+    /// Bytes with a bad checksum or garbage, skipped until the next record (until the end in
+    /// databases of older versions, whose records can't be found without reading the previous).
+    Corrupted { offset: usize, len: usize },
+    /// The checksum is right but the record is not a `ZombieFile`.
+    Undecodable { offset: usize, len: usize },
+}
+
+impl std::fmt::Display for Damage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Damage::Truncated { offset, len } => write!(f, "truncated record at byte {offset} ({len} bytes)"),
+            Damage::Corrupted { offset, len } => write!(f, "corrupted record at byte {offset} ({len} bytes skipped)"),
+            Damage::Undecodable { offset, len } => write!(f, "unreadable record at byte {offset} ({len} bytes skipped)"),
+        }
+    }
+}
+
+/// Reads all the records after the header, skipping the damaged ones.
+/// Every record comes with its offset in the database.
+fn read_records(data: &[u8]) -> (Vec<(usize, ZombieFile)>, Vec<Damage>) {
+    let (mut records, mut damage) = (vec![], vec![]);
+    let mut i = HEADER_LEN;
+    while i < data.len() {
+        match frame_at(data, i) {
+            Some(payload) => {
+                let len = RECORD_HEADER_LEN + payload.len();
+                match rmp_serde::from_slice(payload) {
+                    Ok(file) => records.push((i, file)),
+                    Err(_) => damage.push(Damage::Undecodable { offset: i, len }),
+                }
+                i += len;
+            }
+            None => {
+                let next = find_record(data, i + 1);
+                let len = next.unwrap_or(data.len()) - i;
+                match next.is_none() && data[i..].starts_with(&RECORD_MAGIC) {
+                    true => damage.push(Damage::Truncated { offset: i, len }),
+                    false => damage.push(Damage::Corrupted { offset: i, len }),
+                }
+                i += len;
+            }
+        }
+    }
+    (records, damage)
+}
+
+fn header_bytes() -> Vec<u8> {
+    [&MAGIC[..], &VERSION.to_le_bytes()].concat()
+}
+
+fn frame_into(buff: &mut Vec<u8>, file: &ZombieFile) -> io::Result<()> {
+    let payload = rmp_serde::to_vec_named(file)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "record too big"))?
+        .to_le_bytes();
+    buff.extend_from_slice(&RECORD_MAGIC);
+    buff.extend_from_slice(&len);
+    buff.extend_from_slice(&checksum(&len, &payload).to_le_bytes());
+    buff.extend_from_slice(&payload);
+    Ok(())
+}
+
+// Payload of the record at data[i..] if it's complete and the checksum matches.
+fn frame_at(data: &[u8], i: usize) -> Option<&[u8]> {
+    let header = data.get(i..i + RECORD_HEADER_LEN)?;
+    if header[..4] != RECORD_MAGIC {
+        return None;
+    }
+    let len = &header[4..8];
+    let crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    let start = i + RECORD_HEADER_LEN;
+    let payload = data.get(start..start + u32::from_le_bytes(len.try_into().unwrap()) as usize)?;
+    (checksum(len, payload) == crc).then_some(payload)
+}
+
+// Start of the first valid record at or after `from`.
+fn find_record(data: &[u8], from: usize) -> Option<usize> {
+    (from..data.len().saturating_sub(RECORD_HEADER_LEN - 1))
+        .filter(|&i| data[i..].starts_with(&RECORD_MAGIC))
+        .find(|&i| frame_at(data, i).is_some())
+}
+
+fn checksum(len: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn read_db(db: &Path) -> io::Result<Vec<u8>> {
+    let mut buff = vec![];
+    File::open(db)
+        .map_err(|e| io::Error::new(e.kind(), format!("No database at {:?}: {e}", db)))?
+        .read_to_end(&mut buff)?;
+    Ok(buff)
+}
+
+// None if the data doesn't start with the header (database from before the header existed).
+fn version(data: &[u8], db: &Path) -> io::Result<Option<u32>> {
+    if data.len() < HEADER_LEN || data[..MAGIC.len()] != MAGIC {
+        return Ok(None);
+    }
+    let version = u32::from_le_bytes(data[MAGIC.len()..HEADER_LEN].try_into().unwrap());
+    if version > VERSION {
+        let msg = format!("Database {:?} has format version {version}, this rmls only understands up to {VERSION}. Update rmls", db);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    Ok(Some(version))
+}
+
+// Rewrites a database of an older version in the current format. The original is kept as
+// `<db>.v<version>`.
+// The records before the first damaged one are kept, the damage is reported on stderr.
+fn migrate(db: &Path, data: &[u8], version: u32) -> io::Result<Vec<ZombieFile>> {
+    let (records, damage) = read_legacy(data, version);
+    if let Some(damage) = damage {
+        // nothing in it is a record, it's more likely another file than a damaged database
+        if records.is_empty() {
+            let msg = format!("{:?} is not an rmls database, or is damaged from the start: {damage}", db);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        eprintln!("Warning: {:?}: {damage}, the records after it are lost", db);
+    }
+    let zombie_files: Vec<_> = records.into_iter().map(|(_, file)| file).collect();
+    let backup = with_suffix(db, &format!(".v{version}"));
+    fs::copy(db, &backup)?;
+    replace(db, &zombie_files)?;
+    println!("Upgraded database {:?} to version {VERSION} ({} files). Old one saved as {:?}", db, zombie_files.len(), backup);
+    Ok(zombie_files)
+}
+
+// The records of a database of an older version, with their offsets, up to the first one that
+// can't be read. Without framing, nothing after it can be found.
+fn read_legacy(data: &[u8], version: u32) -> (Vec<(usize, ZombieFile)>, Option<Damage>) {
+    match version {
+        0 => legacy::read_v0(data),
+        1 => legacy::read_v1(data, HEADER_LEN),
+        _ => unreachable!("version {version} is not older than {VERSION}"),
+    }
+}
+
+/// Atomically replaces the database with one containing `files`: the new database is written
+/// next to the old one and renamed over it.
+fn replace(db: &Path, files: &[ZombieFile]) -> io::Result<()> {
+    let tmp = with_suffix(db, ".tmp");
+    let mut data = header_bytes();
+    for file in files {
+        frame_into(&mut data, file)?;
+    }
+    let mut new = File::create(&tmp)?;
+    new.write_all(&data)?;
+    new.sync_all()?;
+    fs::rename(&tmp, db)?;
+    sync_dir(db)
+}
+
+// Makes the creation/rename of `path` durable.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    name.into()
+}
+
+// Formats that aren't written anymore. They must never change, they describe data already on disk.
+mod legacy {
+    use std::io::{self, Cursor};
+    use bincode::Options;
+    use serde::Deserialize;
+    use crate::{Extent, ZombieFile};
+    use super::Damage;
+
+    // Before the header: bincode encoded records one after the other.
+    #[derive(Deserialize)]
+    struct ZombieFileV0 {
+        name: String,
+        len: usize,
+        extents: Vec<ExtentV0>,
+    }
+
+    #[derive(Deserialize)]
+    struct ExtentV0 {
+        start: u64,
+        len: u64,
+    }
+
+    impl From<ZombieFileV0> for ZombieFile {
+        fn from(value: ZombieFileV0) -> Self {
+            ZombieFile {
+                name: value.name,
+                len: value.len,
+                extents: value.extents.into_iter()
+                    .map(|e| Extent { start: e.start, len: e.len, ..Default::default() })
+                    .collect(),
+                ..Default::default()
+            }
+        }
+    }
+
+    pub(super) fn read_v0(data: &[u8]) -> (Vec<(usize, ZombieFile)>, Option<Damage>) {
+        let mut zombie_files = vec![];
+        let mut i = 0;
+        while i < data.len() {
+            let mut rest = &data[i..];
+            // a damaged length can't make it allocate more than what's left. The slack lets
+            // records cut in the middle of an integer end as truncated instead of over the limit.
+            let limit = rest.len() as u64 + 8;
+            let options = bincode::DefaultOptions::new().with_fixint_encoding().allow_trailing_bytes().with_limit(limit);
+            match options.deserialize_from::<_, ZombieFileV0>(&mut rest) {
+                Ok(file) => {
+                    zombie_files.push((i, file.into()));
+                    i = data.len() - rest.len();
+                }
+                Err(e) => {
+                    let truncated = matches!(*e, bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof);
+                    return (zombie_files, Some(damage(truncated, i, data.len())));
+                }
+            }
+        }
+        (zombie_files, None)
+    }
+
+    // Version 1: MessagePack records one after the other, without framing, after the header
+    // (`start` bytes).
+    pub(super) fn read_v1(data: &[u8], start: usize) -> (Vec<(usize, ZombieFile)>, Option<Damage>) {
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(start as u64);
+        let mut zombie_files = vec![];
+        while (cursor.position() as usize) < data.len() {
+            let i = cursor.position() as usize;
+            match rmp_serde::from_read(&mut cursor) {
+                Ok(file) => zombie_files.push((i, file)),
+                Err(e) => {
+                    let truncated = match e {
+                        rmp_serde::decode::Error::InvalidMarkerRead(e) | rmp_serde::decode::Error::InvalidDataRead(e) => {
+                            e.kind() == io::ErrorKind::UnexpectedEof
+                        }
+                        _ => false,
+                    };
+                    return (zombie_files, Some(damage(truncated, i, data.len())));
+                }
+            }
+        }
+        (zombie_files, None)
+    }
+
+    fn damage(truncated: bool, offset: usize, end: usize) -> Damage {
+        match truncated {
+            true => Damage::Truncated { offset, len: end - offset },
+            false => Damage::Corrupted { offset, len: end - offset },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(name: &str) -> ZombieFile {
+        ZombieFile { name: name.to_owned(), len: 4096, extents: vec![Extent { start: 8192, len: 4096, ..Default::default() }], ..Default::default() }
+    }
+
+    // a database with `names`, and where every record starts
+    fn database(names: &[&str]) -> (Vec<u8>, Vec<usize>) {
+        let mut data = header_bytes();
+        let mut offsets = vec![];
+        for name in names {
+            offsets.push(data.len());
+            frame_into(&mut data, &file(name)).unwrap();
+        }
+        (data, offsets)
+    }
+
+    fn names(records: &[(usize, ZombieFile)]) -> Vec<&str> {
+        records.iter().map(|(_, f)| f.name.as_str()).collect()
+    }
+
+    #[test]
+    fn reads_every_record() {
+        let (data, offsets) = database(&["a", "b", "c"]);
+        let (records, damage) = read_records(&data);
+        assert_eq!(names(&records), ["a", "b", "c"]);
+        assert_eq!(records.iter().map(|(i, _)| *i).collect::<Vec<_>>(), offsets);
+        assert!(damage.is_empty());
+    }
+
+    #[test]
+    fn truncated_tail() {
+        let (mut data, offsets) = database(&["a", "b"]);
+        data.truncate(data.len() - 3);
+        let (records, damage) = read_records(&data);
+        assert_eq!(names(&records), ["a"]);
+        assert!(matches!(damage[..], [Damage::Truncated { offset, len }] if offset == offsets[1] && len == data.len() - offsets[1]));
+    }
+
+    #[test]
+    fn truncated_header() {
+        let (mut data, offsets) = database(&["a", "b"]);
+        data.truncate(offsets[1] + 6);
+        let (records, damage) = read_records(&data);
+        assert_eq!(names(&records), ["a"]);
+        assert!(matches!(damage[..], [Damage::Truncated { offset, len: 6 }] if offset == offsets[1]));
+    }
+
+    #[test]
+    fn resyncs_after_bad_checksum() {
+        let (mut data, offsets) = database(&["a", "b", "c"]);
+        data[offsets[1] + RECORD_HEADER_LEN + 2] ^= 0xFF;
+        let (records, damage) = read_records(&data);
+        assert_eq!(names(&records), ["a", "c"]);
+        assert!(matches!(damage[..], [Damage::Corrupted { offset, len }] if offset == offsets[1] && len == offsets[2] - offsets[1]));
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let (data, offsets) = database(&["a", "b"]);
+        // garbage with a record marker in it, not followed by a valid record
+        let garbage = [&b"xx"[..], &RECORD_MAGIC, &[0xFF; 9]].concat();
+        let data = [&data[..offsets[1]], &garbage, &data[offsets[1]..]].concat();
+        let (records, damage) = read_records(&data);
+        assert_eq!(names(&records), ["a", "b"]);
+        assert_eq!(records[1].0, offsets[1] + garbage.len());
+        assert!(matches!(damage[..], [Damage::Corrupted { offset, len }] if offset == offsets[1] && len == garbage.len()));
+    }
+
+    #[test]
+    fn undecodable_payload() {
+        let mut data = header_bytes();
+        let payload = [0xC1]; // never used in MessagePack
+        let len = (payload.len() as u32).to_le_bytes();
+        data.extend_from_slice(&RECORD_MAGIC);
+        data.extend_from_slice(&len);
+        data.extend_from_slice(&checksum(&len, &payload).to_le_bytes());
+        data.extend_from_slice(&payload);
+        let (records, damage) = read_records(&data);
+        assert!(records.is_empty());
+        assert!(matches!(damage[..], [Damage::Undecodable { offset: HEADER_LEN, len }] if len == RECORD_HEADER_LEN + 1));
+    }
+
+    #[test]
+    fn find_record_needs_a_valid_checksum() {
+        let (data, offsets) = database(&["a", "b"]);
+        assert_eq!(find_record(&data, 0), Some(offsets[0]));
+        assert_eq!(find_record(&data, offsets[0] + 1), Some(offsets[1]));
+        assert_eq!(find_record(&data, offsets[1] + 1), None);
+        let mut bad = data.clone();
+        bad[offsets[1] + 4] ^= 1;
+        assert_eq!(find_record(&bad, offsets[0] + 1), None);
+        // too short for a record header
+        assert_eq!(find_record(&RECORD_MAGIC, 0), None);
+    }
+
+    // a record of the format from before the header: bincode, with fixed size integers
+    fn v0_record(name: &str, extents: &[(u64, u64)]) -> Vec<u8> {
+        let mut data = (name.len() as u64).to_le_bytes().to_vec();
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&4096u64.to_le_bytes());
+        data.extend_from_slice(&(extents.len() as u64).to_le_bytes());
+        for (start, len) in extents {
+            data.extend_from_slice(&start.to_le_bytes());
+            data.extend_from_slice(&len.to_le_bytes());
+        }
+        data
+    }
+
+    fn v0_database(names: &[&str]) -> (Vec<u8>, Vec<usize>) {
+        let (mut data, mut offsets) = (vec![], vec![]);
+        for name in names {
+            offsets.push(data.len());
+            data.extend(v0_record(name, &[(8192, 4096)]));
+        }
+        (data, offsets)
+    }
+
+    #[test]
+    fn reads_v0() {
+        let (data, offsets) = v0_database(&["a", "bc"]);
+        let (records, damage) = read_legacy(&data, 0);
+        assert_eq!(names(&records), ["a", "bc"]);
+        assert_eq!(records.iter().map(|(i, _)| *i).collect::<Vec<_>>(), offsets);
+        assert_eq!((records[1].1.len, records[1].1.extents[0].start, records[1].1.extents[0].len), (4096, 8192, 4096));
+        assert!(damage.is_none());
+    }
+
+    #[test]
+    fn reads_v1() {
+        let mut data = [&MAGIC[..], &1u32.to_le_bytes()].concat();
+        let mut offsets = vec![];
+        for name in ["a", "b"] {
+            offsets.push(data.len());
+            data.extend(rmp_serde::to_vec_named(&file(name)).unwrap());
+        }
+        let (records, damage) = read_legacy(&data, 1);
+        assert_eq!(names(&records), ["a", "b"]);
+        assert_eq!(records.iter().map(|(i, _)| *i).collect::<Vec<_>>(), offsets);
+        assert!(damage.is_none());
+        data.truncate(data.len() - 2);
+        let (records, damage) = read_legacy(&data, 1);
+        assert_eq!(names(&records), ["a"]);
+        assert!(matches!(damage, Some(Damage::Truncated { offset, len }) if offset == offsets[1] && len == data.len() - offsets[1]));
+    }
+
+    #[test]
+    fn torn_v0_keeps_what_precedes() {
+        let (mut data, offsets) = v0_database(&["a", "b", "c"]);
+        data.truncate(offsets[2] + 5);
+        let (records, damage) = read_legacy(&data, 0);
+        assert_eq!(names(&records), ["a", "b"]);
+        assert!(matches!(damage, Some(Damage::Truncated { offset, len: 5 }) if offset == offsets[2]));
+    }
+
+    #[test]
+    fn v0_with_a_huge_length() {
+        let (mut data, offsets) = v0_database(&["a", "b"]);
+        // the length of the name of "b"
+        data[offsets[1]..offsets[1] + 8].copy_from_slice(&0x7F00_0000_0000_0000u64.to_le_bytes());
+        let (records, damage) = read_legacy(&data, 0);
+        assert_eq!(names(&records), ["a"]);
+        assert!(matches!(damage, Some(Damage::Corrupted { offset, .. }) if offset == offsets[1]));
+    }
+
+    #[test]
+    fn migrates_v0() {
+        let dir = env::temp_dir().join(format!("rmls-test-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let db = dir.join(DB);
+        let (data, offsets) = v0_database(&["a", "b", "c"]);
+        fs::write(&db, &data[..offsets[2] + 3]).unwrap();
+        let files = files_from_db(&db).unwrap();
+        assert_eq!(files.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(version(&fs::read(&db).unwrap(), &db).unwrap(), Some(VERSION));
+        assert_eq!(fs::read(with_suffix(&db, ".v0")).unwrap(), &data[..offsets[2] + 3]);
+        // not a database at all: left alone
+        let other = dir.join("other");
+        fs::write(&other, b"just some text, not records").unwrap();
+        assert_eq!(files_from_db(&other).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&other).unwrap(), b"just some text, not records");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fsck_of_a_torn_v0() {
+        let dir = env::temp_dir().join(format!("rmls-test-fsck-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        let (db, output) = (dir.join(DB), dir.join("repaired"));
+        let (data, offsets) = v0_database(&["a", "b", "a", "c"]);
+        fs::write(&db, &data[..offsets[3] + 3]).unwrap();
+        let report = fsck(&db, &output).unwrap();
+        assert_eq!((report.version, report.records, report.kept), (0, 3, 2));
+        assert!(matches!(report.problems[..], [
+            Problem::Damaged(Damage::Truncated { offset, len: 3 }),
+            Problem::Duplicate { offset: duplicate, first: 0, .. },
+        ] if offset == offsets[3] && duplicate == offsets[2]));
+        let (records, damage) = read_records(&fs::read(&output).unwrap());
+        assert_eq!(names(&records), ["a", "b"]);
+        assert!(damage.is_empty());
+        // the database itself isn't modified
+        assert_eq!(fs::read(&db).unwrap(), &data[..offsets[3] + 3]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use std::{collections::HashMap, fs::{self, read_dir, File}, io, os::unix::fs::{FileExt, MetadataExt}, path::Path};
+use crate::{btrfs, ext4, loopdev, xfs, Volume, ZombieFile};
+
+const MOUNTINFO: &str = "/proc/self/mountinfo";
+const BY_UUID: &str = "/dev/disk/by-uuid";
+
+/// Sets `fs_uuid` and `loop_backing` of the files in `entries`, from the `dev` in their metadata.
+pub(crate) fn set_filesystems(entries: &mut [ZombieFile]) {
+    let mut filesystems: HashMap<u64, (Option<String>, Option<loopdev::LoopBacking>)> = HashMap::new();
+    for entry in entries.iter_mut() {
+        if let Some(m) = entry.metadata {
+            let (uuid, backing) = filesystems.entry(m.dev).or_insert_with(|| (fs_uuid(m.dev, &entry.name), loopdev::backing(m.dev)));
+            entry.fs_uuid = uuid.clone();
+            entry.loop_backing = backing.clone();
+        }
+    }
+}
+
+// UUID of the filesystem with st_dev `dev`, where `path` is. udev's links say it without reading
+// the device, which usually only root can do.
+fn fs_uuid(dev: u64, path: &str) -> Option<String> {
+    let by_uuid = read_dir(BY_UUID).into_iter().flatten().flatten()
+        .find(|entry| fs::metadata(entry.path()).is_ok_and(|m| m.rdev() == dev))
+        .and_then(|entry| entry.file_name().into_string().ok());
+    by_uuid.or_else(|| superblock_uuid(&mount_source(dev, path).ok()?).ok().flatten())
+}
+
+/// Block device of the filesystem a deleted file was in, the one mounted with its `st_dev`
+/// according to `/proc/self/mountinfo`. If it was a loop device that's detached now (or backed
+/// by another file), its image file.
+pub fn device_of(file: &ZombieFile) -> io::Result<String> {
+    let metadata = file.metadata
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Don't know the device of {:?}, it was deleted by an older rmls", file.name)))?;
+    match &file.loop_backing {
+        Some(backing) if loopdev::backing(metadata.dev).as_ref() != Some(backing) => Ok(backing.file.clone()),
+        _ => mount_source(metadata.dev, &file.name),
+    }
+}
+
+fn mount_source(dev: u64, path: &str) -> io::Result<String> {
+    let mountinfo = fs::read_to_string(MOUNTINFO)?;
+    source_in(&mountinfo, dev, Path::new(path)).ok_or_else(|| {
+        let msg = format!("No device mounted for device number {}, pass it with --recover <device> <output>", format_dev(dev));
+        io::Error::new(io::ErrorKind::NotFound, msg)
+    })
+}
+
+// | id | parent | major:minor | root | mount point | options | optional fields... | - | type | source | super options |
+// The device mounted with st_dev `dev`. The subvolumes of btrfs have a st_dev of their own that
+// no mount has, then it's the mount `path` is in: the one with the longest mount point over it.
+fn source_in(mountinfo: &str, dev: u64, path: &Path) -> Option<String> {
+    let wanted = format_dev(dev);
+    // (major:minor, mount point, source)
+    let mounts: Vec<_> = mountinfo.lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let (dev, mount_point) = (fields.nth(2)?, unescape(fields.nth(1)?));
+            let (_, after) = line.split_once(" - ")?;
+            Some((dev, mount_point, unescape(after.split(' ').nth(1)?)))
+        })
+        .collect();
+    let mount = match mounts.iter().any(|(dev, ..)| *dev == wanted) {
+        true => mounts.iter().find(|(dev, _, source)| *dev == wanted && source.starts_with('/')),
+        // the last one mounted hides the others at the same mount point
+        false => mounts.iter().filter(|(_, mount_point, _)| path.starts_with(mount_point))
+            .max_by_key(|(_, mount_point, _)| mount_point.len())
+            .filter(|(.., source)| source.starts_with('/')),
+    };
+    mount.map(|(.., source)| source.clone())
+}
+
+// mountinfo writes spaces, tabs, newlines and backslashes as \ooo
+fn unescape(field: &str) -> String {
+    let mut out = vec![];
+    let bytes = field.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|o| u8::from_str_radix(std::str::from_utf8(o).ok()?, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(c)) => {
+                out.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// bytes at the start of a filesystem with the superblocks of ext4 and XFS
+const SUPERBLOCK_AREA: usize = 2048;
+
+/// UUID in the superblock of `device`, None if it isn't a filesystem rmls knows.
+pub fn superblock_uuid(device: &str) -> io::Result<Option<String>> {
+    let device = open_device(device)?;
+    uuid_in(|buf, pos| device.read_exact_at(buf, pos))
+}
+
+// Opens `device` to read from it, with its name in the error.
+pub(crate) fn open_device(device: &str) -> io::Result<File> {
+    File::open(device).map_err(|e| io::Error::new(e.kind(), format!("Error opening device {:?}: {e}", device)))
+}
+
+// UUID in the superblock of the filesystem `read` reads from.
+pub(crate) fn uuid_in(read: impl Fn(&mut [u8], u64) -> io::Result<()>) -> io::Result<Option<String>> {
+    let mut start = vec![0; SUPERBLOCK_AREA];
+    match read(&mut start, 0) {
+        // too small for a filesystem
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let uuid = match ext4::superblock_uuid(&start).or_else(|| xfs::superblock_uuid(&start)) {
+        Some(uuid) => Some(uuid),
+        None => btrfs::superblock_uuid(&read)?,
+    };
+    Ok(uuid.map(|uuid| format_uuid(&uuid)))
+}
+
+pub(crate) fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{b:02x}")).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+// major:minor, as /sys/dev/block and device-mapper tables name devices
+pub(crate) fn format_dev(dev: u64) -> String {
+    format!("{}:{}", libc::major(dev), libc::minor(dev))
+}
+
+/// Fails if `device` isn't the filesystem `file` was deleted from (nor a device under it, see
+/// [`Volume`]): reading its extents there would give garbage. Files recorded without the UUID
+/// of their filesystem can't be checked.
+pub fn check_device(file: &ZombieFile, device: &str) -> io::Result<()> {
+    Volume::open(file, device).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_octal() {
+        assert_eq!(unescape("/dev/sda1"), "/dev/sda1");
+        assert_eq!(unescape(r"/mnt/my\040disk"), "/mnt/my disk");
+        assert_eq!(unescape(r"a\011b\012c\134d"), "a\tb\nc\\d");
+        assert_eq!(unescape(r"\303\251t\303\251"), "été");
+    }
+
+    const MOUNTS: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+30 22 0:30 / /tmp rw shared:5 - tmpfs tmpfs rw
+41 22 0:45 /home /home rw,relatime shared:20 - btrfs /dev/nvme0n1p3 rw,subvol=/home
+42 41 0:46 /data /home/me/my\\040data rw,relatime shared:21 - btrfs /dev/sdb1 rw,subvol=/data
+";
+
+    #[test]
+    fn finds_mounts_by_device_number() {
+        let dev = |major, minor| libc::makedev(major, minor);
+        assert_eq!(source_in(MOUNTS, dev(8, 2), Path::new("/etc/x")).as_deref(), Some("/dev/sda2"));
+        // not a block device
+        assert_eq!(source_in(MOUNTS, dev(0, 30), Path::new("/tmp/x")), None);
+        assert_eq!(source_in(MOUNTS, dev(9, 9), Path::new("relative")), None);
+    }
+
+    #[test]
+    fn finds_mounts_of_subvolumes_by_path() {
+        // subvolumes under the one mounted have a device number of their own
+        let subvolume = libc::makedev(0, 52);
+        assert_eq!(source_in(MOUNTS, subvolume, Path::new("/home/me/snapshots/x")).as_deref(), Some("/dev/nvme0n1p3"));
+        assert_eq!(source_in(MOUNTS, subvolume, Path::new("/home/me/my data/x")).as_deref(), Some("/dev/sdb1"));
+        // a path component, not a prefix of one
+        assert_eq!(source_in(MOUNTS, subvolume, Path::new("/homes/x")).as_deref(), Some("/dev/sda2"));
+        assert_eq!(source_in(MOUNTS, subvolume, Path::new("/tmp/x")), None);
+    }
+
+    #[test]
+    fn keeps_what_isnt_an_escape() {
+        // not octal, too short, or more than a byte
+        assert_eq!(unescape(r"a\9bc"), r"a\9bc");
+        assert_eq!(unescape(r"end\04"), r"end\04");
+        assert_eq!(unescape(r"trailing\"), r"trailing\");
+        assert_eq!(unescape(r"\400"), r"\400");
+    }
+}
@@ -0,0 +1,256 @@
+use std::{collections::HashMap, io};
+use crate::{bytes::{le_u16, le_u32}, volume::ReadAt, Allocation, Volume};
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_LEN: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const BG_BLOCK_UNINIT: u16 = 0x2;
+
+/// UUID in the superblock of an ext4 filesystem, from its first bytes. None if it isn't one.
+pub(crate) fn superblock_uuid(start: &[u8]) -> Option<[u8; 16]> {
+    let sb = start.get(SUPERBLOCK_OFFSET as usize..SUPERBLOCK_OFFSET as usize + SUPERBLOCK_LEN)?;
+    (le_u16(sb, 0x38) == MAGIC).then(|| sb[0x68..0x78].try_into().unwrap_or_default())
+}
+
+/// An ext4 filesystem, read through its volume, to look up blocks in its block bitmaps.
+pub struct Ext4 {
+    read: ReadAt,
+    block_size: u64,
+    // blocks per bit of the bitmap, more than one with bigalloc
+    cluster_ratio: u64,
+    first_data_block: u64,
+    blocks_count: u64,
+    blocks_per_group: u64,
+    desc_size: u64,
+    meta_bg: Option<u64>,
+    sparse_super: bool,
+    uuid: [u8; 16],
+    // bitmaps read so far, None if the group has no bitmap yet (all of it free)
+    bitmaps: HashMap<u64, Option<Vec<u8>>>,
+}
+
+impl Ext4 {
+    /// Reads the superblock of `volume`, fails if it isn't ext2/3/4.
+    pub fn open(volume: Volume) -> io::Result<Self> {
+        Self::read_from(volume.into_read_at())
+    }
+
+    fn read_from(read: ReadAt) -> io::Result<Self> {
+        let mut sb = [0; SUPERBLOCK_LEN];
+        read(&mut sb, SUPERBLOCK_OFFSET)?;
+        if le_u16(&sb, 0x38) != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an ext4 filesystem"));
+        }
+        let log_block_size = le_u32(&sb, 0x18);
+        let log_cluster_size = le_u32(&sb, 0x1C);
+        if log_block_size > 6 || log_cluster_size < log_block_size || log_cluster_size > 30 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid ext4 block size"));
+        }
+        let incompat = le_u32(&sb, 0x60);
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let desc_size = match (is_64bit, le_u16(&sb, 0xFE)) {
+            (true, n) if n >= 64 => n as u64,
+            _ => 32,
+        };
+        let blocks_count = match is_64bit {
+            true => (le_u32(&sb, 0x150) as u64) << 32 | le_u32(&sb, 0x4) as u64,
+            false => le_u32(&sb, 0x4) as u64,
+        };
+        let (block_size, cluster_ratio) = (1024 << log_block_size, 1 << (log_cluster_size - log_block_size));
+        // a block holds a whole number of descriptors
+        if !desc_size.is_power_of_two() || desc_size > u64::min(1024, block_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid ext4 group descriptor size"));
+        }
+        let blocks_per_group = le_u32(&sb, 0x20) as u64;
+        // the bitmap of a group is a block, with a bit for every cluster
+        if blocks_per_group == 0 || (blocks_per_group - 1) / cluster_ratio >= block_size * 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid ext4 blocks per group"));
+        }
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&sb[0x68..0x78]);
+        Ok(Ext4 {
+            read,
+            block_size,
+            cluster_ratio,
+            first_data_block: le_u32(&sb, 0x14) as u64,
+            blocks_count,
+            blocks_per_group,
+            desc_size,
+            meta_bg: (incompat & INCOMPAT_META_BG != 0).then(|| le_u32(&sb, 0x104) as u64),
+            sparse_super: le_u32(&sb, 0x64) & RO_COMPAT_SPARSE_SUPER != 0,
+            uuid,
+            bitmaps: HashMap::new(),
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    // whether `group` has a copy of the superblock (and of the group descriptors)
+    fn has_super(&self, group: u64) -> bool {
+        let power_of = |mut n: u64, base: u64| {
+            while n > 1 && n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        };
+        !self.sparse_super || group <= 1 || power_of(group, 3) || power_of(group, 5) || power_of(group, 7)
+    }
+
+    // where the descriptor of `group` is, in bytes
+    fn descriptor(&self, group: u64) -> u64 {
+        let per_block = self.block_size / self.desc_size;
+        let block = match self.meta_bg {
+            // with meta_bg the descriptors of every meta group are in its first group
+            Some(first) if group / per_block >= first => {
+                let first_group = group / per_block * per_block;
+                self.first_data_block + first_group * self.blocks_per_group + self.has_super(first_group) as u64
+            }
+            _ => self.first_data_block + 1 + group / per_block,
+        };
+        block * self.block_size + group % per_block * self.desc_size
+    }
+
+    fn bitmap(&mut self, group: u64) -> io::Result<Option<&[u8]>> {
+        if !self.bitmaps.contains_key(&group) {
+            let mut desc = vec![0; self.desc_size as usize];
+            (self.read)(&mut desc, self.descriptor(group))?;
+            let mut block = le_u32(&desc, 0) as u64;
+            if self.desc_size >= 64 {
+                block |= (le_u32(&desc, 0x20) as u64) << 32;
+            }
+            let bitmap = match le_u16(&desc, 0x12) & BG_BLOCK_UNINIT {
+                0 => {
+                    let mut bitmap = vec![0; self.block_size as usize];
+                    (self.read)(&mut bitmap, block * self.block_size)?;
+                    Some(bitmap)
+                }
+                _ => None,
+            };
+            self.bitmaps.insert(group, bitmap);
+        }
+        Ok(self.bitmaps[&group].as_deref())
+    }
+
+    fn allocated(&mut self, block: u64) -> io::Result<bool> {
+        let relative = block - self.first_data_block;
+        let bit = relative % self.blocks_per_group / self.cluster_ratio;
+        Ok(match self.bitmap(relative / self.blocks_per_group)? {
+            Some(bitmap) => bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0,
+            None => false,
+        })
+    }
+
+    /// Whether the blocks `first..last` are allocated now.
+    pub(crate) fn allocation(&mut self, first: u64, last: u64) -> io::Result<Allocation> {
+        if first < self.first_data_block || last > self.blocks_count {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Extent outside of the filesystem, wrong device?"));
+        }
+        let mut allocated = 0;
+        // a bit for every cluster
+        let mut block = first;
+        while block < last {
+            allocated += self.allocated(block)? as u64;
+            block = (block / self.cluster_ratio + 1) * self.cluster_ratio;
+        }
+        let clusters = (last - 1) / self.cluster_ratio - first / self.cluster_ratio + 1;
+        Ok(match allocated {
+            0 => Allocation::Free,
+            n if n == clusters => Allocation::Allocated,
+            _ => Allocation::Partial,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_in(disk: Vec<u8>) -> ReadAt {
+        Box::new(move |buf, pos| {
+            let bytes = disk.get(pos as usize..pos as usize + buf.len()).ok_or(io::ErrorKind::UnexpectedEof)?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        })
+    }
+
+    fn set(b: &mut [u8], at: usize, value: u32) {
+        b[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // an image of `len` bytes with a superblock with these (offset, value) fields
+    fn image(len: usize, fields: &[(usize, u32)]) -> Vec<u8> {
+        let mut disk = vec![0; len];
+        let sb = &mut disk[SUPERBLOCK_OFFSET as usize..];
+        sb[0x38..0x3A].copy_from_slice(&MAGIC.to_le_bytes());
+        for &(at, value) in fields {
+            set(sb, at, value);
+        }
+        disk
+    }
+
+    #[test]
+    fn bigalloc_bitmaps() {
+        // blocks of 4 KiB in clusters of 4, 3 groups of 32 blocks (8 clusters), descriptors in
+        // block 1 and bitmaps in blocks 2 and 3
+        let mut disk = image(4 * 4096, &[(0x4, 96), (0x14, 0), (0x18, 2), (0x1C, 4), (0x20, 32)]);
+        set(&mut disk, 4096, 2);
+        set(&mut disk, 4096 + 32, 3);
+        // the last group has no bitmap yet
+        set(&mut disk, 4096 + 64, 1000);
+        disk[4096 + 64 + 0x12] = BG_BLOCK_UNINIT as u8;
+        // clusters 0 and 2 of the first group, 1 of the second
+        disk[2 * 4096] = 0b101;
+        disk[3 * 4096] = 0b10;
+        let mut ext4 = Ext4::read_from(read_in(disk)).unwrap();
+        assert_eq!((ext4.block_size(), ext4.cluster_ratio), (4096, 4));
+        assert_eq!(ext4.allocation(0, 4).unwrap(), Allocation::Allocated);
+        // a block of a cluster is allocated with all of it
+        assert_eq!(ext4.allocation(2, 3).unwrap(), Allocation::Allocated);
+        assert_eq!(ext4.allocation(9, 10).unwrap(), Allocation::Allocated);
+        assert_eq!(ext4.allocation(4, 8).unwrap(), Allocation::Free);
+        assert_eq!(ext4.allocation(2, 6).unwrap(), Allocation::Partial);
+        assert_eq!(ext4.allocation(32, 36).unwrap(), Allocation::Free);
+        assert_eq!(ext4.allocation(36, 40).unwrap(), Allocation::Allocated);
+        assert_eq!(ext4.allocation(64, 96).unwrap(), Allocation::Free);
+        assert_eq!(ext4.allocation(90, 97).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn meta_bg_descriptors() {
+        // blocks of 1 KiB from block 1, 32 descriptors per block, meta groups from the second one
+        let fields = [(0x4, u32::MAX), (0x14, 1), (0x20, 8192), (0x60, INCOMPAT_META_BG), (0x104, 1)];
+        let sparse = Ext4::read_from(read_in(image(2048, &[&fields[..], &[(0x64, RO_COMPAT_SPARSE_SUPER)]].concat()))).unwrap();
+        // the first meta group has its descriptors after the superblock, like without meta_bg
+        assert_eq!(sparse.descriptor(5), 2 * 1024 + 5 * 32);
+        // the others in their first group, after the copy of the superblock if it has one (none
+        // of these with sparse_super)
+        assert_eq!(sparse.descriptor(32), (1 + 32 * 8192) * 1024);
+        assert_eq!(sparse.descriptor(33), (1 + 32 * 8192) * 1024 + 32);
+        assert_eq!(sparse.descriptor(100), (1 + 96 * 8192) * 1024 + 4 * 32);
+        // without sparse_super every group has one
+        let all = Ext4::read_from(read_in(image(2048, &fields))).unwrap();
+        assert_eq!(all.descriptor(32), (1 + 32 * 8192 + 1) * 1024);
+        // 64 bytes per descriptor, 16 per block
+        let fields = [(0x4, u32::MAX), (0x14, 1), (0x20, 8192), (0x60, INCOMPAT_META_BG | INCOMPAT_64BIT), (0xFE, 64), (0x104, 1)];
+        let wide = Ext4::read_from(read_in(image(2048, &fields))).unwrap();
+        assert_eq!(wide.descriptor(15), 2 * 1024 + 15 * 64);
+        assert_eq!(wide.descriptor(17), (1 + 16 * 8192 + 1) * 1024 + 64);
+    }
+
+    #[test]
+    fn invalid_descriptor_size() {
+        for desc_size in [96, 2048] {
+            let disk = image(2048, &[(0x4, 8192), (0x20, 8192), (0x60, INCOMPAT_64BIT), (0xFE, desc_size)]);
+            assert_eq!(Ext4::read_from(read_in(disk)).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData), "{desc_size}");
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::io;
+use crate::{device::format_uuid, xfs, Btrfs, Ext4, Extent, ExtentFlags, Volume, Xfs, ZombieFile};
+
+/// Whether the blocks of an extent are in use again, according to the block bitmaps (free
+/// space btrees on XFS, extent tree on btrfs).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Allocation {
+    /// None of them, nothing has overwritten it yet (as of now).
+    Free,
+    /// Some of them.
+    Partial,
+    /// All of them, the data is almost certainly gone.
+    Allocated,
+    /// It has no blocks of its own to check (delayed allocation, inline data).
+    Unknown,
+}
+
+impl std::fmt::Display for Allocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Allocation::Free => write!(f, "free"),
+            Allocation::Partial => write!(f, "partly allocated"),
+            Allocation::Allocated => write!(f, "allocated"),
+            Allocation::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl Allocation {
+    /// The allocation of a whole file, from the one of its extents.
+    pub fn of_all(extents: &[Allocation]) -> Allocation {
+        let known: Vec<_> = extents.iter().copied().filter(|&a| a != Allocation::Unknown).collect();
+        match known.first() {
+            None => Allocation::Unknown,
+            Some(&first) if known.iter().all(|&a| a == first) => first,
+            _ => Allocation::Partial,
+        }
+    }
+}
+
+/// A filesystem whose free space rmls can read, to tell whether the blocks of deleted files were
+/// reused.
+pub enum Filesystem {
+    Ext4(Ext4),
+    Xfs(Xfs),
+    Btrfs(Btrfs),
+}
+
+impl Filesystem {
+    /// Reads the superblock of the filesystem `file` was in, through `device` like
+    /// [`Volume::open`]. Fails if it's neither ext2/3/4, XFS nor btrfs.
+    pub fn open(file: &ZombieFile, device: &str) -> io::Result<Self> {
+        let volume = Volume::open(file, device)?;
+        if volume.is_btrfs() {
+            return Ok(Filesystem::Btrfs(Btrfs::open(volume)?));
+        }
+        let mut start = [0; 1024];
+        match volume.read_device(&mut start, 0) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+            res => res?,
+        }
+        match xfs::superblock_uuid(&start) {
+            Some(_) => Ok(Filesystem::Xfs(Xfs::open(volume)?)),
+            None => Ok(Filesystem::Ext4(Ext4::open(volume)?)),
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        match self {
+            Filesystem::Ext4(ext4) => ext4.block_size(),
+            Filesystem::Xfs(xfs) => xfs.block_size(),
+            Filesystem::Btrfs(btrfs) => btrfs.block_size(),
+        }
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        match self {
+            Filesystem::Ext4(ext4) => ext4.uuid(),
+            Filesystem::Xfs(xfs) => xfs.uuid(),
+            Filesystem::Btrfs(btrfs) => btrfs.uuid(),
+        }
+    }
+
+    /// Whether the blocks of every extent of `file` are allocated now. Fails if the file was in a
+    /// filesystem with another UUID or block size, it can't be this one.
+    pub fn file_allocation(&mut self, file: &ZombieFile) -> io::Result<Vec<Allocation>> {
+        let block_size = self.block_size();
+        if file.fs_uuid.as_ref().is_some_and(|recorded| !recorded.eq_ignore_ascii_case(&format_uuid(&self.uuid()))) {
+            let msg = format!("{:?} was in another filesystem, with UUID {}", file.name, file.fs_uuid.as_deref().unwrap_or_default());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        if file.block_size != 0 && file.block_size != block_size {
+            let msg = format!("{:?} was in a filesystem with blocks of {} bytes, this one has {}", file.name, file.block_size, block_size);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        file.extents.iter().map(|e| self.allocation(e)).collect()
+    }
+
+    /// Whether the blocks of `extent` are allocated now. Unknown for extents that have no blocks
+    /// of their own (yet).
+    pub fn allocation(&mut self, extent: &Extent) -> io::Result<Allocation> {
+        let no_blocks = ExtentFlags::UNKNOWN | ExtentFlags::DELALLOC | ExtentFlags::DATA_INLINE | ExtentFlags::NOT_ALIGNED;
+        if extent.len == 0 || extent.flags().intersects(no_blocks) {
+            return Ok(Allocation::Unknown);
+        }
+        let block_size = self.block_size();
+        let (first, last) = (extent.start / block_size, (extent.start + extent.len).div_ceil(block_size));
+        match self {
+            Filesystem::Ext4(ext4) => ext4.allocation(first, last),
+            Filesystem::Xfs(xfs) => xfs.allocation(first, last),
+            Filesystem::Btrfs(btrfs) => btrfs.allocation(first, last),
+        }
+    }
+}
+
+/// Whether every extent of `file` is allocated again in the filesystem (ext4, XFS or btrfs) it
+/// was in, read through `device`.
+pub fn allocation<A: AsRef<str>>(file: &ZombieFile, device: A) -> io::Result<Vec<Allocation>> {
+    Filesystem::open(file, device.as_ref())?.file_allocation(file)
+}
@@ -1,97 +1,384 @@
 mod tui;
+mod db;
+mod meta;
+mod tree;
+mod links;
+mod stash;
+mod manifest;
+mod status;
+mod ext4;
+mod device;
+mod volume;
+mod partition;
+mod loopdev;
+mod btrfs;
+mod xfs;
+mod filesystem;
+mod bytes;
 pub use tui::tui;
-use std::{fs::{canonicalize, metadata, remove_file, remove_dir_all, File, Metadata, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::Path, usize};
+pub use db::{db_path, files_from_db, fsck, Damage, Fsck, Problem};
+pub use meta::{restore_metadata, FileMetadata, Timestamp, Xattr};
+pub use tree::{needs_device, recover_tree};
+pub use stash::stash_max;
+pub use manifest::{write_damage_map, Manifest, Recovered};
+pub use status::{status, Status};
+pub use ext4::Ext4;
+pub use xfs::Xfs;
+pub use btrfs::Btrfs;
+pub use filesystem::{allocation, Allocation, Filesystem};
+pub use device::{check_device, device_of, superblock_uuid};
+pub use volume::Volume;
+pub use partition::{partitions, Partition};
+pub use loopdev::LoopBacking;
+use std::{collections::hash_map::RandomState, fs::{canonicalize, read_link, remove_file, remove_dir_all, symlink_metadata, File, Metadata}, hash::{BuildHasher, Hasher}, io::{self, Read, Seek, SeekFrom, Write}, os::unix::{ffi::OsStrExt, fs::MetadataExt}, path::{Path, PathBuf}, ffi::CString};
 use fiemap::{fiemap, FiemapExtent};
+pub use fiemap::FiemapExtentFlags as ExtentFlags;
 use serde::{Deserialize, Serialize};
 
-const BLOCK_SIZE: u64 = 4096;
-const DB: &'static str = "DB.bin";
+// for records from before the block size of the filesystem was kept
+const DEFAULT_BLOCK_SIZE: u64 = 4096;
 
-pub fn remove<P: AsRef<Path>>(path: P) {
-    let filename = canonicalize(&path).unwrap();
+/// Records `path` (every file inside it if it's a directory) in the database and deletes it.
+/// The content of files up to `stash_max` bytes is copied into the database, see [`stash_max`].
+/// Nothing is deleted if it couldn't be recorded.
+pub fn remove<P: AsRef<Path>, D: AsRef<Path>>(path: P, db: D, stash_max: u64) -> io::Result<()> {
+    let filename = absolute(path.as_ref())?;
 
-    append_file_db(&filename);
+    let mut entries = vec![];
+    append_file_db(&filename, &mut entries, None, stash_max)?;
+    links::find_outside_links(&filename, &mut entries);
+    device::set_filesystems(&mut entries);
+    let mut db = db::open_for_append(db.as_ref())?;
+    for entry in &entries {
+        db.write_zombie_file(entry)?;
+    }
+    drop(db);
 
-    let metadata = metadata(&path).unwrap();
-    if metadata.is_file() {
-        remove_file(&path).expect("Couldn't remove file");
+    // what was recorded: without a trailing slash, a symlink to a directory is the link
+    let metadata = symlink_metadata(&filename)?;
+    if metadata.is_dir() {
+        remove_dir_all(&filename)
     } else {
-        remove_dir_all(&path).expect("Couldn't remove directory");
+        remove_file(&filename)
     }
 }
 
-pub fn files_from_db() -> Vec<ZombieFile> {
-    let mut db = File::open(DB).expect("No database");
-    let mut buff = vec![];
-    db.read_to_end(&mut buff).expect("TODO: BETTER");
-    let mut zombie_files = vec![];
-    let mut i = 0;
-    while i < buff.len() {
-        let curr = read_zombie_file(&buff[i..]);
-        i += bincode::serialized_size(&curr)
-            .expect("Error serializing zombie_file") as usize;
-        zombie_files.push(curr);
+// Like canonicalize, but if `path` is a symlink it's not followed, we want the link.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if parent.as_os_str().is_empty() => Ok(canonicalize(".")?.join(name)),
+        (Some(parent), Some(name)) => Ok(canonicalize(parent)?.join(name)),
+        _ => canonicalize(path),
     }
-    zombie_files
 }
 
-pub fn recover_file<A: AsRef<str>, W: Write> (file: &ZombieFile, device: A, mut output: W) {
-    let mut device = File::open(device.as_ref()).expect("Error opening device");
-    let mut length = file.len as u64;
-    let mut buff = [0; 100 * BLOCK_SIZE as usize];
-    for extent in &file.extents {
-        let offset = extent.start;
-        device.seek(SeekFrom::Start(offset)).expect("Error offseting block");
-
-        let mut bytes_to_read = match length.checked_sub(extent.len) {
-            Some(n) => {
-                length = n;
-                extent.len
-            }
-            None => length,
-        };
+/// Writes the content of `file` read from `device` to `output`. Every extent goes to its logical
+/// offset, so the holes of sparse files are left where they were (as holes, if `output` is a file).
+/// Writers that can't seek can be wrapped in [`ZeroFill`].
+/// The content written is hashed and compared with the hashes taken when it was deleted. With
+/// `intact_only` the parts that don't match are left as zeros, see [`Recovered::damaged`].
+pub fn recover_file<A: AsRef<str>, W: Write + Seek> (file: &ZombieFile, device: A, output: W, intact_only: bool) -> io::Result<Recovered> {
+    let mut output = manifest::Checked::new(output, file, intact_only);
+    if let Some(stash) = &file.stash {
+        output.write_at(0, &stash::unstash(stash)?)?;
+        return output.finish();
+    }
+    let volume = Volume::open(file, device.as_ref())?;
+    let length = file.len as u64;
+    let mut buff = vec![0; 100 * file.block_size() as usize];
+    for (mut offset, extent) in file.placed_extents() {
+        if offset >= length {
+            continue;
+        }
+        let mut bytes_to_read = u64::min(extent.len, length - offset);
+        // left as a hole, see `ZombieFile::warnings`
+        if !extent.readable() {
+            continue;
+        }
+        let mut position = extent.start;
         while bytes_to_read > 0 {
-            let Ok(just_read) = device.read(&mut buff) else {
-                continue;
-            };
-            let read = match bytes_to_read < just_read as u64 {
-                true => bytes_to_read as usize,
-                false => just_read,
-            };
-            let mut written = 0;
-            while written < read {
-                match output.write(&buff[written..read]) {
-                    Ok(w) => written += w,
-                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
-                    err => panic!("Unespected error while writting:\n{:?}", err),
-                }
-            }
+            let read = u64::min(bytes_to_read, buff.len() as u64) as usize;
+            volume.read_exact_at(&mut buff[..read], position)
+                .map_err(|e| io::Error::new(e.kind(), format!("Error reading the extent at byte {} of the device: {e}", extent.start)))?;
+            output.write_at(offset, &buff[..read])?;
+            offset += read as u64;
+            position += read as u64;
             bytes_to_read -= read as u64;
         }
     };
+    output.finish()
+}
+
+/// Whether a recovered file is the one that was deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// Same content it had when it was deleted.
+    Verified,
+    /// Different content, some blocks were probably reused by other files.
+    Mismatch,
+    /// There's no hash to compare with, it was deleted by an older rmls.
+    NoHash,
+}
+
+impl std::fmt::Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verification::Verified => write!(f, "verified"),
+            Verification::Mismatch => write!(f, "mismatch"),
+            Verification::NoHash => write!(f, "no hash"),
+        }
+    }
+}
+
+/// Lets [`recover_file`] write to something that can't seek (a pipe, stdout...): seeking forward
+/// writes zeros instead. Seeking backwards is an error.
+pub struct ZeroFill<W: Write> {
+    inner: W,
+    position: u64,
+}
+
+impl<W: Write> ZeroFill<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, position: 0 }
+    }
+}
+
+impl<W: Write> Write for ZeroFill<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Seek for ZeroFill<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+            SeekFrom::End(_) => None,
+        };
+        let Some(target) = target.filter(|&t| t >= self.position) else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Can only seek forward"));
+        };
+        io::copy(&mut io::repeat(0).take(target - self.position), self)?;
+        Ok(self.position)
+    }
+}
+
+/// Recreates the FIFO, socket or device node `file` at `path`.
+pub fn recover_special<P: AsRef<Path>>(file: &ZombieFile, path: P) -> io::Result<()> {
+    let metadata = file.metadata.filter(|_| file.kind == FileKind::Special)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is not a special file", file.name)))?;
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // the permissions are restored with the rest of the metadata
+    match unsafe { libc::mknod(path.as_ptr(), metadata.mode & libc::S_IFMT | 0o600, metadata.rdev) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Recreates the symlink `file` at `path`.
+pub fn recover_symlink<P: AsRef<Path>>(file: &ZombieFile, path: P) -> io::Result<()> {
+    let target = file.target.as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is not a symlink", file.name)))?;
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ZombieFile {
     pub name: String,
     pub len: usize,
     pub extents: Vec<Extent>,
+    /// None for files deleted before rmls recorded it.
+    #[serde(default)]
+    pub metadata: Option<FileMetadata>,
+    #[serde(default)]
+    pub xattrs: Vec<Xattr>,
+    #[serde(default)]
+    pub kind: FileKind,
+    /// Identifies the entry, so the ones inside a directory can point to it. 0 for entries
+    /// recorded before directories were.
+    #[serde(default)]
+    pub id: u64,
+    /// Id of the directory this entry was in, if it was deleted with it.
+    #[serde(default)]
+    pub parent: Option<u64>,
+    /// Where the link pointed to, for symlinks.
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Another name (hard link) of the file that wasn't deleted with it, so its data wasn't freed.
+    #[serde(default)]
+    pub other_link: Option<String>,
+    /// It had hard links that weren't deleted with it. `other_link` is one of them, unless the
+    /// search for them gave up.
+    #[serde(default)]
+    pub linked_outside: bool,
+    /// Compressed copy of the content, for small files. Recovering them doesn't need the device.
+    #[serde(default, with = "serde_bytes")]
+    pub stash: Option<Vec<u8>>,
+    /// BLAKE3 of the content when it was deleted.
+    #[serde(default, with = "serde_bytes")]
+    pub hash: Option<Vec<u8>>,
+    /// Hashes of every part of the content, to tell which ones a recovery got right. Not kept
+    /// for stashed files, the stash can't be overwritten.
+    #[serde(default)]
+    pub manifest: Option<Manifest>,
+    /// Block size of the filesystem it was in. 0 for records from before it was kept.
+    #[serde(default)]
+    pub block_size: u64,
+    /// UUID of the filesystem it was in, to make sure it's recovered from the right device.
+    /// Its device number (`st_dev`) is in `metadata`.
+    #[serde(default)]
+    pub fs_uuid: Option<String>,
+    /// The image file, if the filesystem was in a loop device. It can be recovered from it
+    /// after the loop device is gone.
+    #[serde(default)]
+    pub loop_backing: Option<LoopBacking>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileKind {
+    #[default]
+    File,
+    /// Has no extents, its content are the entries whose `parent` is its `id`.
+    Directory,
+    /// Has no extents, it's recreated from `target`.
+    Symlink,
+    /// A FIFO, socket or device node. Has no content, it's recreated from the mode and `rdev`
+    /// of its metadata.
+    Special,
 }
 
 impl ZombieFile {
-    fn new(name: String, m: Metadata, extents: Vec<Extent>) -> Self {
+    fn new(name: String, m: Metadata, extents: Vec<Extent>, xattrs: Vec<Xattr>, parent: Option<u64>) -> Self {
+        let kind = match m.file_type() {
+            t if t.is_dir() => FileKind::Directory,
+            t if t.is_symlink() => FileKind::Symlink,
+            t if t.is_file() => FileKind::File,
+            _ => FileKind::Special,
+        };
         Self {
             name,
-            len: m.len() as usize,
+            len: if kind == FileKind::File { m.len() as usize } else { 0 },
             extents,
+            metadata: Some(FileMetadata::from(&m)),
+            xattrs,
+            kind,
+            id: new_id(),
+            parent,
+            target: None,
+            other_link: None,
+            linked_outside: false,
+            stash: None,
+            hash: None,
+            manifest: None,
+            block_size: 0,
+            fs_uuid: None,
+            loop_backing: None,
+        }
+    }
+
+    fn verify(&self, hash: blake3::Hash) -> Verification {
+        match &self.hash {
+            Some(expected) if expected[..] == hash.as_bytes()[..] => Verification::Verified,
+            Some(_) => Verification::Mismatch,
+            None => Verification::NoHash,
+        }
+    }
+
+    /// Block size of the filesystem the file was in, 4096 if it wasn't recorded.
+    pub fn block_size(&self) -> u64 {
+        match self.block_size {
+            0 => DEFAULT_BLOCK_SIZE,
+            n => n,
+        }
+    }
+
+    /// The extents with their offset in the file, in order. Records from before logical offsets
+    /// were kept have the extents back to back.
+    pub fn placed_extents(&self) -> Vec<(u64, Extent)> {
+        let mut extents = self.extents.clone();
+        if extents.iter().all(|e| e.logical.is_some()) {
+            extents.sort_by_key(|e| e.logical);
+        }
+        let mut logical = 0;
+        extents.into_iter().map(|extent| {
+            let offset = extent.logical.unwrap_or(logical);
+            logical = offset + extent.len;
+            (offset, extent)
+        }).collect()
+    }
+
+    /// What can't be recovered as it was, because of how the extents were stored.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if self.stash.is_some() {
+            return warnings;
+        }
+        if self.kind == FileKind::File && self.extents.is_empty() && self.len > 0 {
+            warnings.push("no extents recorded (it couldn't be read when it was deleted), recovered as zeros".to_owned());
         }
+        let mut warn = |flag, what: &str| {
+            let n = self.extents.iter().filter(|e| e.flags().contains(flag)).count();
+            if n > 0 {
+                warnings.push(format!("{n} {what}"));
+            }
+        };
+        warn(ExtentFlags::UNKNOWN, "extent(s) with no address on disk (delayed allocation), recovered as zeros");
+        warn(ExtentFlags::ENCODED, "compressed extent(s), cannot recover raw, recovered as zeros");
+        warn(ExtentFlags::DATA_ENCRYPTED, "encrypted extent(s), cannot recover raw, recovered as zeros");
+        warn(ExtentFlags::DATA_INLINE, "extent(s) stored inline with metadata, cannot recover raw, recovered as zeros");
+        warn(ExtentFlags::DATA_TAIL, "extent(s) packed with other files, cannot recover raw, recovered as zeros");
+        warn(ExtentFlags::SHARED, "extent(s) shared with other files (reflinks, snapshots), still in use so they weren't freed");
+        warnings
+    }
+
+    /// `other_link`, if it's still a name of this file: then the data is still there, it was never freed.
+    pub fn reachable_via(&self) -> Option<&str> {
+        let (other, metadata) = (self.other_link.as_deref()?, self.metadata.as_ref()?);
+        let current = symlink_metadata(other).ok()?;
+        (current.ino() == metadata.ino && current.dev() == metadata.dev).then_some(other)
     }
 }
 
+// Random, so ids of different runs of rmls don't collide.
+fn new_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(std::process::id() as u64);
+    hasher.finish().max(1)
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
 pub struct Extent {
+    /// Where it is in the device.
     pub start: u64,
     pub len: u64,
+    /// Where it is in the file. None for records from before it was kept.
+    #[serde(default)]
+    pub logical: Option<u64>,
+    /// [`ExtentFlags`] as given by FIEMAP.
+    #[serde(default)]
+    pub flags: u32,
+}
+
+impl Extent {
+    pub fn flags(&self) -> ExtentFlags {
+        ExtentFlags::from_bits_retain(self.flags)
+    }
+
+    /// Whether the content of the extent can be read raw from the device at `start`.
+    /// Unwritten (preallocated) extents are zeros, no need to read them.
+    pub fn readable(&self) -> bool {
+        let unreadable = ExtentFlags::UNKNOWN | ExtentFlags::DELALLOC | ExtentFlags::ENCODED | ExtentFlags::DATA_ENCRYPTED
+            | ExtentFlags::DATA_INLINE | ExtentFlags::DATA_TAIL | ExtentFlags::UNWRITTEN;
+        !self.flags().intersects(unreadable)
+    }
 }
 
 impl From<FiemapExtent> for Extent {
@@ -99,59 +386,92 @@ impl From<FiemapExtent> for Extent {
         Extent {
             start: value.fe_physical,
             len: value.fe_length,
+            logical: Some(value.fe_logical),
+            flags: value.fe_flags.bits(),
         }
     }
 }
 
-fn write_zombie_file<W: io::Write>(writer: &mut W, file: ZombieFile) {
-    while let Err(err) = bincode::serialize_into(&mut *writer, &file) {
-        match *err {
-            bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::Interrupted => {
-                println!("Interrupted: {:?}", e);
-            }
-            err => panic!("Unespected error while writting:\n{:?}", err),
-        }
+// Directories go before what's inside them, the recovery relies on it.
+fn append_file_db<P: AsRef<Path>>(filename: P, db: &mut Vec<ZombieFile>, parent: Option<u64>, stash_max: u64) -> io::Result<()> {
+    let filename = filename.as_ref();
+    // which file of the tree it's about, the ones inside it add their own name
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{:?}: {e}", filename));
+    let Some(id) = append_entry(filename, db, parent, stash_max).map_err(with_path)? else {
+        return Ok(());
+    };
+    for entry in std::fs::read_dir(filename).map_err(with_path)? {
+        append_file_db(entry.map_err(with_path)?.path(), db, Some(id), stash_max)?;
     }
+    Ok(())
 }
 
-fn read_zombie_file(data: &[u8]) -> ZombieFile {
-    loop {
-        match bincode::deserialize_from(data) {
-            Ok(v) => break v,
-            Err(err) =>  {
-                match *err {
-                    bincode::ErrorKind::Io(e) if e.kind() == io::ErrorKind::Interrupted => {}
-                    err => panic!("Unespected error while writting:\n{:?}", err),
-                }
-            }
+// Records `filename`, but not what's inside it. Returns its id if it's a directory.
+fn append_entry(filename: &Path, db: &mut Vec<ZombieFile>, parent: Option<u64>, stash_max: u64) -> io::Result<Option<u64>> {
+    // not following symlinks, their target could be anywhere
+    let Ok(metadata) = symlink_metadata(filename) else {
+        println!("File: '{:?}' doesn't exist", filename);
+        return Ok(None)
+    };
+    let name = filename.to_str()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Non UTF-8 file name"))?
+        .to_owned();
+    let xattrs = meta::read_xattrs(filename)?;
+    if metadata.is_symlink() {
+        let target = read_link(filename)?;
+        let target = target.to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Non UTF-8 link target: {:?}", target)))?
+            .to_owned();
+        println!("Adding symlink: {:?} -> {:?}", filename, target);
+        let zombie = ZombieFile { target: Some(target), ..ZombieFile::new(name, metadata, vec![], xattrs, parent) };
+        db.push(zombie);
+        return Ok(None);
+    }
+    if metadata.is_dir() {
+        println!("Adding directory: {:?}", filename);
+        let zombie = ZombieFile::new(name, metadata, vec![], xattrs, parent);
+        let id = zombie.id;
+        db.push(zombie);
+        return Ok(Some(id));
+    }
+    // opening a FIFO waits for a writer, and a device node is no file to sync
+    if !metadata.is_file() {
+        println!("Adding special file: {:?}", filename);
+        db.push(ZombieFile::new(name, metadata, vec![], xattrs, parent));
+        return Ok(None);
+    }
+    let block_size = meta::block_size(filename)?;
+    // with delayed allocation the blocks of data that is still in the page cache aren't chosen
+    // yet and FIEMAP doesn't know where they'll be. Writing it back makes the filesystem choose.
+    match File::open(filename) {
+        Ok(file) => file.sync_all()?,
+        // we can delete files we can't read (mode 000, someone else's in our directory). FIEMAP
+        // needs it open too, only what's known without opening it is recorded.
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            println!("Warning: can't read {:?} ({e}), its content won't be recoverable", filename);
+            db.push(ZombieFile { block_size, ..ZombieFile::new(name, metadata, vec![], xattrs, parent) });
+            return Ok(None);
         }
+        Err(e) => return Err(e),
     }
-}
-
-fn append_file_db<P: AsRef<Path>>(filename: P) {
-    let Ok(fiemap) = fiemap(&filename) else { 
-        println!("File: '{:?}' doesn't exist or is broken symlink", filename.as_ref());
-        return
+    let fiemap = fiemap(filename)?
+        .map(|x| x.map(Extent::from))
+        .collect::<io::Result<Vec<_>>>()?;
+    // small files are kept whole. So are inline ones (ext4 inline_data), their content is in the inode.
+    let inline = fiemap.iter().any(|e| e.flags().contains(ExtentFlags::DATA_INLINE));
+    let stash = match metadata.len() <= stash_max || inline {
+        true => Some(stash::stash(filename, metadata.len())?),
+        false => None,
     };
-    let fiemap: Vec<_> = fiemap
-        .filter_map(|x| match x {
-            Ok(x) => Some(Extent::from(x)),
-            Err(err) => panic!("last OS error: {err:?}\n{:?}", &filename.as_ref()),
-        }).collect();
-    let metadata = metadata(&filename)
-        .expect("Error reading metadata from file");
-    if metadata.is_file() {
-        // APPEND THE EXTENT INFORMATION INTO THE DB
-        println!("Adding file: {:?}", filename.as_ref());
-        let zombie = ZombieFile::new(filename.as_ref().to_str().unwrap().to_owned(), metadata, fiemap);
-        let mut db = OpenOptions::new()
-            .create(true).append(true)
-            .open(DB).expect("Error opening DB");
-        write_zombie_file(&mut db, zombie);
-        return;
-    }
-    for entry in std::fs::read_dir(filename).expect("path probably doesn't exist") {
-        let path = entry.unwrap().path();
-        append_file_db(path.to_str().unwrap().to_owned());
+    if stash.is_none() && fiemap.iter().any(|e| e.flags().intersects(ExtentFlags::UNKNOWN | ExtentFlags::DELALLOC)) {
+        let msg = "It still has extents without a place on disk after syncing it, it couldn't be recovered. Nothing was deleted";
+        return Err(io::Error::other(msg));
     }
+    let (hash, manifest) = manifest::hash_file(filename)?;
+    let manifest = stash.is_none().then_some(manifest);
+    // APPEND THE EXTENT INFORMATION INTO THE DB
+    println!("Adding file: {:?}", filename);
+    let zombie = ZombieFile { stash, hash: Some(hash), manifest, block_size, ..ZombieFile::new(name, metadata, fiemap, xattrs, parent) };
+    db.push(zombie);
+    Ok(None)
 }
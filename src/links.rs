@@ -0,0 +1,76 @@
+use std::{collections::{HashMap, HashSet}, fs::{read_dir, symlink_metadata}, os::unix::fs::MetadataExt, path::{Path, PathBuf}};
+use crate::{FileKind, ZombieFile};
+
+// directory entries read looking for the other names of the files of a filesystem. Reading all
+// of it could take minutes on a big one, the files are only marked as linked elsewhere then.
+const SEARCH_BUDGET: usize = 100_000;
+
+/// Sets `other_link` of the files in `entries` (everything deleted with `deleted`) that have
+/// names outside of `deleted`: their data is still reachable through them.
+///
+/// Nothing says where those names are, so they're searched starting in the directory of
+/// `deleted` and going up until the root of the filesystem. Hard links tend to be close. The
+/// search gives up after [`SEARCH_BUDGET`] entries, files with other names are still marked with
+/// `linked_outside`.
+pub(crate) fn find_outside_links(deleted: &Path, entries: &mut [ZombieFile]) {
+    // names of every inode inside the deleted tree
+    let mut names: HashMap<(u64, u64), u64> = HashMap::new();
+    for entry in entries.iter().filter(|e| e.kind == FileKind::File) {
+        if let Some(m) = entry.metadata.filter(|m| m.nlink > 1) {
+            *names.entry((m.dev, m.ino)).or_default() += 1;
+        }
+    }
+    let mut wanted: HashMap<u64, HashSet<u64>> = HashMap::new();
+    for entry in entries.iter().filter(|e| e.kind == FileKind::File) {
+        if let Some(m) = entry.metadata.filter(|m| m.nlink > 1 && m.nlink > names.get(&(m.dev, m.ino)).copied().unwrap_or(0)) {
+            wanted.entry(m.dev).or_default().insert(m.ino);
+        }
+    }
+    let mut found: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    for (dev, inodes) in &wanted {
+        let mut inodes = inodes.clone();
+        let mut budget = SEARCH_BUDGET;
+        let mut searched = deleted;
+        while let Some(dir) = searched.parent() {
+            if inodes.is_empty() || budget == 0 || symlink_metadata(dir).map(|m| m.dev() != *dev).unwrap_or(true) {
+                break;
+            }
+            search(dir, searched, *dev, &mut inodes, &mut found, &mut budget);
+            searched = dir;
+        }
+    }
+    for entry in entries.iter_mut() {
+        if let Some(m) = entry.metadata {
+            entry.linked_outside = entry.kind == FileKind::File && wanted.get(&m.dev).is_some_and(|inodes| inodes.contains(&m.ino));
+            entry.other_link = found.get(&(m.dev, m.ino)).and_then(|p| p.to_str()).map(str::to_owned);
+        }
+    }
+}
+
+// Looks for names of `inodes` under `dir` without going into `skip` (already searched or deleted)
+// nor into other filesystems. The ones found are moved from `inodes` to `found`. Every entry read
+// is taken from `budget`.
+fn search(dir: &Path, skip: &Path, dev: u64, inodes: &mut HashSet<u64>, found: &mut HashMap<(u64, u64), PathBuf>, budget: &mut usize) {
+    let Ok(entries) = read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if inodes.is_empty() || *budget == 0 {
+            return;
+        }
+        *budget -= 1;
+        let path = entry.path();
+        // doesn't follow symlinks
+        let Ok(m) = entry.metadata() else {
+            continue;
+        };
+        if path == skip || m.dev() != dev {
+            continue;
+        }
+        if m.is_dir() {
+            search(&path, skip, dev, inodes, found, budget);
+        } else if inodes.remove(&m.ino()) {
+            found.insert((dev, m.ino()), path);
+        }
+    }
+}
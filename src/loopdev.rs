@@ -0,0 +1,34 @@
+use std::{fs::{self, File}, io, path::Path};
+use fiemap::fiemap;
+use serde::{Deserialize, Serialize};
+use crate::{device::format_dev, Extent};
+
+/// The image file behind a loop device, and where the filesystem starts in it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoopBacking {
+    pub file: String,
+    pub offset: u64,
+}
+
+/// What backs the loop device `dev`, None if it isn't one.
+pub(crate) fn backing(dev: u64) -> Option<LoopBacking> {
+    let sys = Path::new("/sys/dev/block").join(format_dev(dev)).join("loop");
+    let file = fs::read_to_string(sys.join("backing_file")).ok()?;
+    // the kernel appends it to deleted files, they can't be found by their name anymore
+    let file = file.trim_end_matches('\n').trim_end_matches(" (deleted)").to_owned();
+    let offset = fs::read_to_string(sys.join("offset")).ok()?.trim().parse().ok()?;
+    Some(LoopBacking { file, offset })
+}
+
+/// Where the image file of `backing` is in the device of the filesystem it's in, by FIEMAP:
+/// `(offset in the image, Extent)` in order.
+pub(crate) fn image_extents(backing: &LoopBacking) -> io::Result<Vec<(u64, Extent)>> {
+    let path = Path::new(&backing.file);
+    // see `append_file_db`, the blocks of what's still in the page cache aren't chosen yet
+    File::open(path)?.sync_all()?;
+    let mut extents = fiemap(path)?
+        .map(|e| e.map(|e| (e.fe_logical, Extent::from(e))))
+        .collect::<io::Result<Vec<_>>>()?;
+    extents.sort_by_key(|(logical, _)| *logical);
+    Ok(extents)
+}
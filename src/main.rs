@@ -1,47 +1,199 @@
 use std::io::{self, Write};
+use std::collections::HashMap;
 use std::fs::File;
-use std::path::PathBuf;
-use rmls::ZombieFile;
+use std::path::{Path, PathBuf};
+use rmls::{Allocation, FileKind, Verification, ZombieFile};
 
-const INPUT_MSG_RECOVER: &'static str = "Expected Input: <device> <output file>";
-const USAGE: &'static str = "USAGE:\n1. rm <file1> <file2> <file3> ..\n2. rm --recover <device> <output>\n3. rm --tui (For interactive selection of files)";
+const INPUT_MSG_RECOVER: &str = "Expected Input: [<device>] <output file>";
+const USAGE: &str = "USAGE:\n1. rm [--stash-max <bytes>] <file1> <file2> <file3> ..\n2. rm --recover [--skip-security-xattrs] [--intact-only] [<device>] <output> (<output> is a directory when recovering one)\n3. rm --tui (For interactive selection of files)\n\
+4. rm db fsck [<repaired copy>] (Check the database and write a copy without the damaged records)\n\
+5. rm status [<device>] (Tell which deleted files can still be recovered, without recovering them)\n\
+Without <device>, the one the file was in is found in /proc/self/mountinfo. Any of them accepts --db <path> to use a database other than $RMLS_DB or $XDG_DATA_HOME/rmls/DB.bin";
 
 
-fn recover(device: String, output_name: String) {
-    let zombie_files = rmls::files_from_db();
+// the device given, or the one `file` was in
+fn device_for(device: &Option<String>, file: &ZombieFile) -> io::Result<String> {
+    match device {
+        Some(device) => Ok(device.clone()),
+        None => rmls::device_of(file),
+    }
+}
+
+fn recover(db: &Path, device: Option<String>, output_name: String, skip_security_xattrs: bool, intact_only: bool) -> io::Result<()> {
+    let zombie_files = rmls::files_from_db(db)?;
+    // whether the blocks of the files were reused, when the device is ext4, XFS or btrfs and can be read
+    // by device and filesystem UUID, a disk can have several of them in its partitions
+    let mut filesystems: HashMap<(String, Option<String>), Option<rmls::Filesystem>> = HashMap::new();
+    let mut blocks = |file: &ZombieFile| {
+        let device = device_for(&device, file).ok()?;
+        let filesystem = filesystems.entry((device, file.fs_uuid.clone()))
+            .or_insert_with_key(|(device, _)| rmls::Filesystem::open(file, device).ok()).as_mut()?;
+        match Allocation::of_all(&filesystem.file_allocation(file).ok()?) {
+            Allocation::Free => Some(", blocks free"),
+            Allocation::Partial => Some(", some blocks allocated again"),
+            Allocation::Allocated => Some(", blocks allocated again, probably overwritten"),
+            Allocation::Unknown => None,
+        }
+    };
     println!("Files:");
-    let print_name = |t: (usize, &ZombieFile)| println!("{:?}", t);
-    zombie_files
-    .iter()
-    .enumerate()
-    .for_each(print_name);
+    let mut print_name = |(i, file): (usize, &ZombieFile)| match file.kind {
+        FileKind::Directory => println!("{i}: {}/", file.name),
+        FileKind::Symlink => println!("{i}: {} -> {}", file.name, file.target.as_deref().unwrap_or("?")),
+        FileKind::Special => println!("{i}: {} (special file)", file.name),
+        FileKind::File => match (file.reachable_via(), &file.stash) {
+            (Some(other), _) => println!("{i}: {} ({} bytes, data still reachable via {other})", file.name, file.len),
+            (None, _) if file.linked_outside && file.other_link.is_none() => {
+                println!("{i}: {} ({} bytes, data still reachable via another hard link)", file.name, file.len)
+            }
+            (None, Some(_)) => println!("{i}: {} ({} bytes, copy in the database)", file.name, file.len),
+            (None, None) => println!("{i}: {} ({} bytes{})", file.name, file.len, blocks(file).unwrap_or_default()),
+        }
+    };
+    for (i, file) in zombie_files.iter().enumerate() {
+        print_name((i, file));
+        for warning in file.warnings() {
+            println!("    {warning}");
+        }
+    }
 
     let mut index = String::with_capacity(5);
     io::stdin().read_line(&mut index).expect("Error reading input");
     let Ok(index) = index.trim().parse() else {
         println!("Expected number >= 0, got: {}", index.trim());
-        return
+        return Ok(())
     };
     let Some(file) = zombie_files.get::<usize>(index) else {
         println!("Index out of bounds: Len = {}; idx = {}", zombie_files.len(), index);
-        return
+        return Ok(())
     };
 
-    let output = File::create(output_name).unwrap();
-    rmls::recover_file(file, device, output);
+    // stashed files don't need the device, their content is in the database
+    let device = match device_for(&device, file) {
+        Err(_) if !rmls::needs_device(&zombie_files, index) => String::new(),
+        device => device?,
+    };
+    let recovered = match file.kind {
+        FileKind::Directory => rmls::recover_tree(&zombie_files, index, device, &output_name, skip_security_xattrs, intact_only)?,
+        FileKind::Symlink => {
+            rmls::recover_symlink(file, &output_name)?;
+            return rmls::restore_metadata(file, &output_name, skip_security_xattrs);
+        }
+        FileKind::Special => {
+            rmls::recover_special(file, &output_name)?;
+            return rmls::restore_metadata(file, &output_name, skip_security_xattrs);
+        }
+        FileKind::File => {
+            if rmls::needs_device(&zombie_files, index) {
+                rmls::check_device(file, &device)?;
+            }
+            let output = File::create(&output_name)?;
+            let recovered = rmls::recover_file(file, device, output, intact_only)?;
+            rmls::restore_metadata(file, &output_name, skip_security_xattrs)?;
+            println!("{output_name}: {recovered}");
+            vec![(PathBuf::from(&output_name), recovered)]
+        }
+    };
+    if recovered.iter().any(|(_, r)| !r.damaged.is_empty()) {
+        let map = format!("{output_name}.damaged");
+        rmls::write_damage_map(&map, &recovered)?;
+        println!("Damaged byte ranges written to {map}{}", if intact_only { ", they were left as zeros" } else { "" });
+    }
+    let mismatches: Vec<_> = recovered.iter().filter(|(_, r)| r.verification == Verification::Mismatch).collect();
+    if !mismatches.is_empty() {
+        println!("{} of {} files don't have the content they had when deleted:", mismatches.len(), recovered.len());
+        for (path, _) in mismatches {
+            println!("  {:?}", path);
+        }
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// removes `flag <value>` from the arguments, wherever it is.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Option<String> {
+    let i = args.iter().position(|x| x.trim() == flag)?;
+    args.remove(i);
+    if i == args.len() {
+        println!("Expected: {flag} <value>");
+        std::process::exit(1);
+    }
+    Some(args.remove(i))
+}
+
+fn fsck(db: &Path, output: Option<String>) -> io::Result<()> {
+    let output = output.map(PathBuf::from).unwrap_or_else(|| {
+        let mut name = db.as_os_str().to_owned();
+        name.push(".fsck");
+        name.into()
+    });
+    let report = rmls::fsck(db, &output)?;
+    println!("Database {:?} (format version {})", db, report.version);
+    for problem in &report.problems {
+        println!("  {problem}");
+    }
+    println!("{} records read, {} problems. Repaired copy with {} records written to {:?}",
+        report.records, report.problems.len(), report.kept, output);
+    if !report.problems.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn status(db: &Path, device: Option<String>) -> io::Result<()> {
+    let zombie_files = rmls::files_from_db(db)?;
+    for (i, file) in zombie_files.iter().enumerate().filter(|(_, f)| f.kind == FileKind::File) {
+        match device_for(&device, file).and_then(|device| rmls::status(file, device)) {
+            Ok(status) => println!("{i}: {} ({} bytes): {status}", file.name, file.len),
+            Err(e) => println!("{i}: {} ({} bytes): couldn't check it: {e}", file.name, file.len),
+        }
+    }
+    Ok(())
+}
+
+// removes `flag` from the arguments, wherever it is. True if it was there.
+fn take_switch(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|x| x.trim() != flag);
+    len != args.len()
 }
 
 fn main() -> std::io::Result<()> {
     // --recover --tui
-    let mut args = std::env::args();
-    let files_to_remove = match args.nth(1) {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let db = rmls::db_path(take_flag(&mut args, "--db").map(PathBuf::from));
+    let stash_max = take_flag(&mut args, "--stash-max").map(|x| match x.trim().parse() {
+        Ok(n) => n,
+        Err(_) => {
+            println!("Expected: --stash-max <bytes>, got: {x}");
+            std::process::exit(1);
+        }
+    });
+    let skip_security_xattrs = take_switch(&mut args, "--skip-security-xattrs");
+    let intact_only = take_switch(&mut args, "--intact-only");
+    let mut args = args.into_iter();
+    let files_to_remove = match args.next() {
         Some(m) if m.trim().eq("--recover") => {
-            let (Some(dev), Some(output)) = (args.next(), args.next()) else {
-                println!("{}", INPUT_MSG_RECOVER);
-                return Ok(());
+            let (device, output) = match (args.next(), args.next()) {
+                (Some(device), Some(output)) => (Some(device), output),
+                (Some(output), None) => (None, output),
+                _ => {
+                    println!("{}", INPUT_MSG_RECOVER);
+                    return Ok(());
+                }
             };
-            recover(dev, output);
-            return Ok(())
+            return recover(&db, device, output, skip_security_xattrs, intact_only);
+        }
+        Some(m) if m.trim().eq("db") => {
+            return match args.next().as_deref().map(str::trim) {
+                Some("fsck") => fsck(&db, args.next()),
+                _ => {
+                    println!("Expected: db fsck [<repaired copy>]");
+                    Ok(())
+                }
+            };
+        }
+        Some(m) if m.trim().eq("status") => {
+            return status(&db, args.next());
         }
         Some(m) if m.trim().eq("--tui") => {
             let Some(dir_name) = args.next() else {
@@ -51,7 +203,7 @@ fn main() -> std::io::Result<()> {
             rmls::tui(dir_name)?
         }
         Some(file) => {
-            args.into_iter()
+            args
                 .chain([file])
                 .map(|x| PathBuf::new().join(x))
                 .collect()
@@ -67,9 +219,12 @@ fn main() -> std::io::Result<()> {
     let mut y_n = String::with_capacity(5);
     io::stdin().read_line(&mut y_n).expect("Error reading input");
     if y_n.trim().is_empty() || y_n.trim().to_lowercase() == "y" {
-        files_to_remove
-            .into_iter()
-            .for_each(rmls::remove);
+        for file in files_to_remove {
+            if let Err(e) = rmls::remove(&file, &db, rmls::stash_max(stash_max)) {
+                println!("Couldn't delete {:?}: {e}", file);
+                std::process::exit(1);
+            }
+        }
     }
     Ok(())
 }
@@ -0,0 +1,348 @@
+use std::{fs::File, io::{self, Read, Seek, SeekFrom, Write}, ops::Range, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+use crate::{Verification, ZombieFile};
+
+const CHUNK: u64 = 64 * 1024;
+// of every chunk hash, the first bytes of its BLAKE3 are enough to tell it changed
+const HASH_LEN: usize = 8;
+const ZEROS: [u8; 4096] = [0; 4096];
+
+/// Hashes of the content of a file, a chunk of `chunk` bytes at a time (the last one can be
+/// shorter), taken when it was deleted. They tell which parts of a recovered file are intact.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub chunk: u64,
+    /// The first bytes of the BLAKE3 of every chunk, one after the other.
+    #[serde(with = "serde_bytes")]
+    pub hashes: Vec<u8>,
+}
+
+impl Manifest {
+    /// Hash of the chunk `i`.
+    pub fn hash(&self, i: usize) -> Option<&[u8]> {
+        self.hashes.get(i * HASH_LEN..(i + 1) * HASH_LEN)
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.hashes.len() / HASH_LEN
+    }
+}
+
+/// What came out of recovering a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovered {
+    pub verification: Verification,
+    /// Byte ranges that don't have the content they had when the file was deleted. Only known
+    /// when it was deleted with a [`Manifest`], without one a mismatch doesn't say where.
+    pub damaged: Vec<Range<u64>>,
+}
+
+// Hashes the whole content and every chunk of it at once.
+struct Hasher {
+    file: blake3::Hasher,
+    chunk: blake3::Hasher,
+    chunk_size: u64,
+    position: u64,
+    hashes: Vec<u8>,
+}
+
+impl Hasher {
+    fn new(chunk_size: u64) -> Self {
+        Self { file: blake3::Hasher::new(), chunk: blake3::Hasher::new(), chunk_size, position: 0, hashes: vec![] }
+    }
+
+    // start of the next chunk
+    fn boundary(&self) -> u64 {
+        (self.position / self.chunk_size + 1).saturating_mul(self.chunk_size)
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = u64::min(data.len() as u64, self.boundary() - self.position) as usize;
+            self.file.update(&data[..n]);
+            self.chunk.update(&data[..n]);
+            self.position += n as u64;
+            if self.position.is_multiple_of(self.chunk_size) {
+                self.end_chunk();
+            }
+            data = &data[n..];
+        }
+    }
+
+    fn end_chunk(&mut self) {
+        self.hashes.extend_from_slice(&self.chunk.finalize().as_bytes()[..HASH_LEN]);
+        self.chunk.reset();
+    }
+
+    // ends the last chunk, if it's shorter, and gives the hash of everything
+    fn finish(&mut self) -> blake3::Hash {
+        if !self.position.is_multiple_of(self.chunk_size) {
+            self.end_chunk();
+        }
+        self.file.finalize()
+    }
+
+    fn chunks(&self) -> usize {
+        self.hashes.len() / HASH_LEN
+    }
+}
+
+// what the manifest keeps of the hash of a chunk
+pub(crate) fn chunk_hash(data: &[u8]) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    hash.copy_from_slice(&blake3::hash(data).as_bytes()[..HASH_LEN]);
+    hash
+}
+
+/// BLAKE3 of the content of `path` and its [`Manifest`].
+pub(crate) fn hash_file(path: &Path) -> io::Result<(Vec<u8>, Manifest)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Hasher::new(CHUNK);
+    let mut buff = vec![0; CHUNK as usize];
+    loop {
+        let read = match file.read(&mut buff) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buff[..read]);
+    }
+    let hash = hasher.finish().as_bytes().to_vec();
+    Ok((hash, Manifest { chunk: CHUNK, hashes: hasher.hashes }))
+}
+
+/// Writes the content of a file as it's recovered, checking it against the hashes taken when
+/// it was deleted. With `intact_only`, the chunks that don't match aren't written: they are left
+/// as holes (zeros), which needs the data of a chunk to wait until all of it was read.
+pub(crate) struct Checked<'a, W: Write + Seek> {
+    output: W,
+    file: &'a ZombieFile,
+    hasher: Hasher,
+    intact_only: bool,
+    // data of the current chunk not written yet, at its offset
+    pending: Vec<(u64, Vec<u8>)>,
+    // chunks already checked
+    checked: usize,
+    // end of the data written
+    end: u64,
+    damaged: Vec<Range<u64>>,
+}
+
+impl<'a, W: Write + Seek> Checked<'a, W> {
+    pub(crate) fn new(output: W, file: &'a ZombieFile, intact_only: bool) -> Self {
+        let chunk_size = file.manifest.as_ref().map(|m| m.chunk).filter(|&c| c > 0).unwrap_or(u64::MAX);
+        Self { output, file, hasher: Hasher::new(chunk_size), intact_only, pending: vec![], checked: 0, end: 0, damaged: vec![] }
+    }
+
+    // only the chunks that can be checked wait
+    fn buffered(&self) -> bool {
+        self.intact_only && self.file.manifest.is_some()
+    }
+
+    /// `data` goes at `offset`, after what was already written. What's in between is a hole.
+    pub(crate) fn write_at(&mut self, offset: u64, mut data: &[u8]) -> io::Result<()> {
+        self.hole(offset)?;
+        // overlapping extents, the first one wins
+        data = &data[usize::min(data.len(), (self.hasher.position - offset) as usize)..];
+        while !data.is_empty() {
+            let n = u64::min(data.len() as u64, self.hasher.boundary() - self.hasher.position) as usize;
+            let position = self.hasher.position;
+            self.hasher.update(&data[..n]);
+            if self.buffered() {
+                self.pending.push((position, data[..n].to_vec()));
+            } else {
+                self.write(position, &data[..n])?;
+            }
+            self.chunk_done()?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    // holes are zeros, nothing is written
+    fn hole(&mut self, until: u64) -> io::Result<()> {
+        while self.hasher.position < until {
+            let n = [until - self.hasher.position, self.hasher.boundary() - self.hasher.position, ZEROS.len() as u64]
+                .into_iter().min().unwrap_or(0) as usize;
+            self.hasher.update(&ZEROS[..n]);
+            self.chunk_done()?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.output.seek(SeekFrom::Start(offset))?;
+        self.output.write_all(data)?;
+        self.end = u64::max(self.end, offset + data.len() as u64);
+        Ok(())
+    }
+
+    // checks the chunk that just ended, if it did
+    fn chunk_done(&mut self) -> io::Result<()> {
+        let (Some(manifest), true) = (&self.file.manifest, self.checked < self.hasher.chunks()) else {
+            return Ok(());
+        };
+        let i = self.checked;
+        self.checked += 1;
+        let start = i as u64 * manifest.chunk;
+        let intact = manifest.hash(i) == self.hasher.hashes.get(i * HASH_LEN..(i + 1) * HASH_LEN);
+        if !intact {
+            match self.damaged.last_mut() {
+                Some(last) if last.end == start => last.end = self.hasher.position,
+                _ => self.damaged.push(start..self.hasher.position),
+            }
+        }
+        for (offset, data) in std::mem::take(&mut self.pending) {
+            if intact {
+                self.write(offset, &data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the file at its length and tells whether it's the one that was deleted.
+    pub(crate) fn finish(mut self) -> io::Result<Recovered> {
+        let length = self.file.len as u64;
+        self.hole(length)?;
+        let hash = self.hasher.finish();
+        self.chunk_done()?;
+        // the file ends with a hole
+        if self.end < length {
+            self.output.seek(SeekFrom::Start(length - 1))?;
+            self.output.write_all(&[0])?;
+        }
+        self.output.flush()?;
+        Ok(Recovered { verification: self.file.verify(hash), damaged: self.damaged })
+    }
+}
+
+impl std::fmt::Display for Recovered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.verification)?;
+        if !self.damaged.is_empty() {
+            let bytes: u64 = self.damaged.iter().map(|r| r.end - r.start).sum();
+            write!(f, ", {bytes} bytes damaged in {} range(s)", self.damaged.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the damaged byte ranges of the recovered files to `path`, one `<file> <start> <end>`
+/// per line (`end` not included).
+pub fn write_damage_map<P: AsRef<Path>>(path: P, recovered: &[(PathBuf, Recovered)]) -> io::Result<()> {
+    let mut map = String::new();
+    for (file, recovered) in recovered {
+        for range in &recovered.damaged {
+            map.push_str(&format!("{} {} {}\n", file.display(), range.start, range.end));
+        }
+    }
+    std::fs::write(path, map)
+}
+
+#[cfg(test)]
+// a single damaged range is what most of them expect
+#[allow(clippy::single_range_in_vec_init)]
+mod tests {
+    use std::{env, fs, io::Cursor};
+    use super::*;
+    use crate::{recover_file, Extent};
+
+    const SMALL: u64 = 16;
+
+    // a file deleted with `content`, hashed in chunks of `SMALL` bytes
+    fn deleted(content: &[u8]) -> ZombieFile {
+        let mut hasher = Hasher::new(SMALL);
+        hasher.update(content);
+        let hash = hasher.finish().as_bytes().to_vec();
+        let manifest = Manifest { chunk: SMALL, hashes: hasher.hashes };
+        ZombieFile { len: content.len(), hash: Some(hash), manifest: Some(manifest), ..Default::default() }
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8 + 1).collect()
+    }
+
+    // recovers `file` from a device that has `data` at byte 4096
+    fn recover_from(file: &ZombieFile, data: &[u8], intact_only: bool) -> (Vec<u8>, Recovered) {
+        let device = env::temp_dir().join(format!("rmls-test-device-{}-{intact_only}", std::process::id()));
+        fs::write(&device, [&[0xEE; 4096][..], data].concat()).unwrap();
+        let extent = Extent { start: 4096, len: data.len() as u64, logical: Some(0), ..Default::default() };
+        let file = ZombieFile { extents: vec![extent], ..file.clone() };
+        let mut output = Cursor::new(vec![]);
+        let recovered = recover_file(&file, device.to_str().unwrap(), &mut output, intact_only);
+        fs::remove_file(&device).unwrap();
+        (output.into_inner(), recovered.unwrap())
+    }
+
+    #[test]
+    fn hole_across_chunks() {
+        let mut data = content(48);
+        data[10..40].fill(0);
+        let file = deleted(&data);
+        let mut output = Cursor::new(vec![]);
+        let mut checked = Checked::new(&mut output, &file, true);
+        checked.write_at(0, &data[..10]).unwrap();
+        checked.write_at(40, &data[40..]).unwrap();
+        let recovered = checked.finish().unwrap();
+        assert_eq!(recovered, Recovered { verification: Verification::Verified, damaged: vec![] });
+        assert_eq!(output.into_inner(), data);
+    }
+
+    #[test]
+    fn damaged_middle_chunk() {
+        let data = content(48);
+        let file = deleted(&data);
+        let mut overwritten = data.clone();
+        overwritten[20] = 0;
+        let (output, recovered) = recover_from(&file, &overwritten, false);
+        assert_eq!(recovered, Recovered { verification: Verification::Mismatch, damaged: vec![16..32] });
+        assert_eq!(output, overwritten);
+    }
+
+    #[test]
+    fn damaged_middle_chunk_left_as_a_hole() {
+        let data = content(48);
+        let file = deleted(&data);
+        let mut overwritten = data.clone();
+        overwritten[20] = 0;
+        let (output, recovered) = recover_from(&file, &overwritten, true);
+        assert_eq!(recovered, Recovered { verification: Verification::Mismatch, damaged: vec![16..32] });
+        let mut expected = data;
+        expected[16..32].fill(0);
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn short_last_chunk() {
+        let data = content(40);
+        let file = deleted(&data);
+        assert_eq!(file.manifest.as_ref().unwrap().chunks(), 3);
+        let (output, recovered) = recover_from(&file, &data, true);
+        assert_eq!(recovered, Recovered { verification: Verification::Verified, damaged: vec![] });
+        assert_eq!(output, data);
+
+        let mut overwritten = data.clone();
+        overwritten[39] = 0;
+        let (output, recovered) = recover_from(&file, &overwritten, true);
+        assert_eq!(recovered, Recovered { verification: Verification::Mismatch, damaged: vec![32..40] });
+        // the file still has its length
+        assert_eq!(output, [&data[..32], &[0; 8]].concat());
+    }
+
+    #[test]
+    fn overlapping_extents() {
+        let data = content(48);
+        let file = deleted(&data);
+        let mut output = Cursor::new(vec![]);
+        let mut checked = Checked::new(&mut output, &file, false);
+        checked.write_at(0, &data[..24]).unwrap();
+        // overlaps the first one, which wins
+        checked.write_at(8, &[0; 20]).unwrap();
+        checked.write_at(28, &data[28..]).unwrap();
+        let recovered = checked.finish().unwrap();
+        // the bytes the second extent didn't have make its chunk damaged
+        assert_eq!(recovered.damaged, [16..32]);
+        assert_eq!(output.into_inner(), [&data[..24], &[0; 4], &data[28..]].concat());
+    }
+}
@@ -0,0 +1,156 @@
+use std::{ffi::{CString, OsStr}, fs::{self, Metadata, Permissions}, io, os::unix::{ffi::OsStrExt, fs::{lchown, MetadataExt, PermissionsExt}}, path::Path};
+use serde::{Deserialize, Serialize};
+use crate::{FileKind, ZombieFile};
+
+// Namespaces only root can write. `security.selinux` could be written by anyone, but not with any value.
+const PRIVILEGED_NAMESPACES: [&str; 2] = ["security.", "trusted."];
+
+/// Metadata of a deleted file, taken from `stat` right before deleting it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+    /// Only informative, the kernel sets it when the file is recovered.
+    pub ctime: Timestamp,
+    pub ino: u64,
+    pub dev: u64,
+    /// Number of names (hard links) the file had.
+    #[serde(default)]
+    pub nlink: u64,
+    /// Device number of device nodes.
+    #[serde(default)]
+    pub rdev: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timestamp {
+    pub secs: i64,
+    pub nsecs: i64,
+}
+
+impl From<&Metadata> for FileMetadata {
+    fn from(m: &Metadata) -> Self {
+        FileMetadata {
+            mode: m.mode(),
+            uid: m.uid(),
+            gid: m.gid(),
+            atime: Timestamp { secs: m.atime(), nsecs: m.atime_nsec() },
+            mtime: Timestamp { secs: m.mtime(), nsecs: m.mtime_nsec() },
+            ctime: Timestamp { secs: m.ctime(), nsecs: m.ctime_nsec() },
+            ino: m.ino(),
+            dev: m.dev(),
+            nlink: m.nlink(),
+            rdev: m.rdev(),
+        }
+    }
+}
+
+/// Extended attribute of a deleted file. POSIX ACLs are stored as the
+/// `system.posix_acl_access` and `system.posix_acl_default` attributes.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Xattr {
+    #[serde(with = "serde_bytes")]
+    pub name: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub value: Vec<u8>,
+}
+
+impl Xattr {
+    fn privileged(&self) -> bool {
+        PRIVILEGED_NAMESPACES.iter().any(|ns| self.name.starts_with(ns.as_bytes()))
+    }
+}
+
+/// Extended attributes of `path` (of the link itself if it's a symlink).
+/// Empty if the filesystem doesn't support them.
+pub(crate) fn read_xattrs(path: &Path) -> io::Result<Vec<Xattr>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = vec![];
+    for name in names {
+        // it could have been removed since we listed it
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.push(Xattr { name: name.as_bytes().to_vec(), value });
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Block size of the filesystem `path` is in.
+pub(crate) fn block_size(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        // the fragment size is the block size, f_bsize is only the preferred size for I/O
+        0 => Ok(match stat.f_frsize {
+            0 => stat.f_bsize as _,
+            n => n as _,
+        }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Gives the recovered file at `path` the owner, extended attributes, permissions and timestamps
+/// it had when it was deleted. Only root can give files away and write the `security.` and
+/// `trusted.` attributes: when that fails it just prints a warning. With `skip_security_xattrs`
+/// those attributes aren't even tried unless we're root.
+pub fn restore_metadata<P: AsRef<Path>>(file: &ZombieFile, path: P, skip_security_xattrs: bool) -> io::Result<()> {
+    let path = path.as_ref();
+    let root = unsafe { libc::geteuid() } == 0;
+    // chown clears the setuid/setgid bits and security.capability, so it goes first
+    if let Some(metadata) = &file.metadata {
+        match lchown(path, Some(metadata.uid), Some(metadata.gid)) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                eprintln!("Warning: couldn't give {:?} to {}:{} ({e}), run as root to keep the owner", path, metadata.uid, metadata.gid);
+            }
+            res => res?,
+        }
+    }
+    for attr in &file.xattrs {
+        if attr.privileged() && skip_security_xattrs && !root {
+            continue;
+        }
+        let name = OsStr::from_bytes(&attr.name);
+        match xattr::set(path, name, &attr.value) {
+            Err(e) if attr.privileged() && e.kind() == io::ErrorKind::PermissionDenied => {
+                eprintln!("Warning: couldn't restore {:?} of {:?} ({e}), run as root or skip them with --skip-security-xattrs", name, path);
+            }
+            // Linux only allows user. attributes on regular files and directories
+            Err(e) if matches!(file.kind, FileKind::Symlink | FileKind::Special) && e.kind() == io::ErrorKind::PermissionDenied => {
+                eprintln!("Warning: couldn't restore {:?} of the special file {:?} ({e})", name, path);
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => {
+                eprintln!("Warning: couldn't restore {:?} of {:?}, the filesystem doesn't support it", name, path);
+            }
+            res => res?,
+        }
+    }
+    // after the ACLs, so the group bits of the mode and the mask of the ACL agree
+    if let Some(metadata) = &file.metadata {
+        // the permissions of a symlink can't change, chmod would change the target's
+        if file.kind != FileKind::Symlink {
+            fs::set_permissions(path, Permissions::from_mode(metadata.mode & 0o7777))?;
+        }
+        set_times(path, metadata)?;
+    }
+    Ok(())
+}
+
+// Last, writing or changing anything else would update them again.
+fn set_times(path: &Path, metadata: &FileMetadata) -> io::Result<()> {
+    let timespec = |t: Timestamp| libc::timespec { tv_sec: t.secs as _, tv_nsec: t.nsecs as _ };
+    let times = [timespec(metadata.atime), timespec(metadata.mtime)];
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -0,0 +1,244 @@
+use std::{fs::File, io, os::unix::fs::FileExt};
+use crate::bytes::{le_u32, le_u64};
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+// disk images don't say their sector size, GPT is looked for with both
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+// entries are 128 bytes, or a power of two times that in later versions
+const GPT_ENTRY_LEN: usize = 128;
+const GPT_ENTRY_LEN_MAX: usize = 4096;
+// logical partitions followed at most, the chain of an extended partition could loop
+const MAX_LOGICAL: usize = 128;
+
+/// A partition of a disk (or disk image), in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub start: u64,
+    pub len: u64,
+}
+
+/// Partitions in the GPT or MBR partition table of `disk`. Empty if it has none.
+pub fn partitions(disk: &File) -> io::Result<Vec<Partition>> {
+    partitions_in(&|buf, pos| disk.read_exact_at(buf, pos))
+}
+
+// Partitions in the partition table of the disk `read` reads from.
+fn partitions_in(read: &impl Fn(&mut [u8], u64) -> io::Result<()>) -> io::Result<Vec<Partition>> {
+    let mut mbr = [0; 512];
+    match read(&mut mbr, 0) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(vec![]),
+        res => res?,
+    }
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(vec![]);
+    }
+    let entries: Vec<&[u8]> = mbr[446..510].chunks(16).collect();
+    if entries.iter().any(|e| e[4] == MBR_PROTECTIVE) {
+        for sector in SECTOR_SIZES {
+            if let Some(partitions) = gpt(read, sector)? {
+                return Ok(partitions);
+            }
+        }
+    }
+    let mut partitions = vec![];
+    for entry in entries {
+        let (kind, start, len) = (entry[4], le_u32(entry, 8) as u64 * 512, le_u32(entry, 12) as u64 * 512);
+        match kind {
+            0 => {}
+            k if MBR_EXTENDED.contains(&k) => partitions.extend(logical(read, start)?),
+            _ => partitions.push(Partition { start, len }),
+        }
+    }
+    Ok(partitions)
+}
+
+// The logical partitions of the extended partition at `extended`. Every one has a boot record
+// before it, with the partition (relative to the record) and where the next record is (relative
+// to the extended partition).
+fn logical(read: &impl Fn(&mut [u8], u64) -> io::Result<()>, extended: u64) -> io::Result<Vec<Partition>> {
+    let mut partitions = vec![];
+    let mut record = extended;
+    for _ in 0..MAX_LOGICAL {
+        let mut ebr = [0; 512];
+        read(&mut ebr, record)?;
+        if ebr[510..512] != MBR_SIGNATURE {
+            break;
+        }
+        let (this, next) = (&ebr[446..462], &ebr[462..478]);
+        if this[4] != 0 {
+            partitions.push(Partition { start: record + le_u32(this, 8) as u64 * 512, len: le_u32(this, 12) as u64 * 512 });
+        }
+        if next[4] == 0 {
+            break;
+        }
+        record = extended + le_u32(next, 8) as u64 * 512;
+    }
+    Ok(partitions)
+}
+
+// The partitions in the GPT of the disk, if it has one with sectors of `sector` bytes.
+fn gpt(read: &impl Fn(&mut [u8], u64) -> io::Result<()>, sector: u64) -> io::Result<Option<Vec<Partition>>> {
+    let mut header = vec![0; sector as usize];
+    match read(&mut header, sector) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let header_len = le_u32(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE || !(92..=header.len()).contains(&header_len) {
+        return Ok(None);
+    }
+    // the checksum is computed with its own field as zeros
+    let mut checked = header[..header_len].to_vec();
+    checked[16..20].fill(0);
+    if crc32fast::hash(&checked) != le_u32(&header, 16) {
+        return Ok(None);
+    }
+    let (entries_lba, count, entry_len) = (le_u64(&header, 72), le_u32(&header, 80) as usize, le_u32(&header, 84) as usize);
+    if entry_len == 0 || !entry_len.is_multiple_of(GPT_ENTRY_LEN) || entry_len > GPT_ENTRY_LEN_MAX || count > 4096 {
+        return Ok(None);
+    }
+    let Some(entries_at) = entries_lba.checked_mul(sector) else {
+        return Ok(None);
+    };
+    let mut entries = vec![0; count * entry_len];
+    read(&mut entries, entries_at)?;
+    let partitions = entries.chunks(entry_len)
+        // unused entries have no type
+        .filter(|entry| entry[..16].iter().any(|&b| b != 0))
+        // entries that end before they start, or past any disk, are skipped
+        .filter_map(|entry| {
+            let (first, last) = (le_u64(entry, 32), le_u64(entry, 40));
+            let sectors = last.checked_add(1)?.checked_sub(first)?;
+            Some(Partition { start: first.checked_mul(sector)?, len: sectors.checked_mul(sector)? })
+        })
+        .collect();
+    Ok(Some(partitions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_in(disk: &[u8]) -> impl Fn(&mut [u8], u64) -> io::Result<()> + '_ {
+        |buf, pos| {
+            let bytes = disk.get(pos as usize..pos as usize + buf.len()).ok_or(io::ErrorKind::UnexpectedEof)?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    // a boot record at `at` with these (type, first sector, sectors) entries
+    fn boot_record(disk: &mut [u8], at: usize, entries: &[(u8, u32, u32)]) {
+        for (i, (kind, first, sectors)) in entries.iter().enumerate() {
+            let entry = &mut disk[at + 446 + i * 16..at + 462 + i * 16];
+            entry[4] = *kind;
+            entry[8..12].copy_from_slice(&first.to_le_bytes());
+            entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        }
+        disk[at + 510..at + 512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    // a disk with a protective MBR and a GPT with these (first, last) sectors
+    fn gpt_disk(sector: usize, entry_len: usize, parts: &[(u64, u64)]) -> Vec<u8> {
+        let mut disk = vec![0; sector * 8];
+        boot_record(&mut disk, 0, &[(MBR_PROTECTIVE, 1, u32::MAX)]);
+        let entries = 2 * sector;
+        for (i, (first, last)) in parts.iter().enumerate() {
+            let entry = &mut disk[entries + i * entry_len..];
+            entry[..16].fill(0xAB);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+        }
+        let header = &mut disk[sector..sector + 92];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&(entry_len as u32).to_le_bytes());
+        let crc = crc32fast::hash(header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk
+    }
+
+    #[test]
+    fn no_table() {
+        assert_eq!(partitions_in(&read_in(&[0; 1024])).unwrap(), []);
+        assert_eq!(partitions_in(&read_in(&[0; 100])).unwrap(), []);
+    }
+
+    #[test]
+    fn mbr() {
+        let mut disk = vec![0; 512];
+        boot_record(&mut disk, 0, &[(0x83, 2048, 100), (0, 0, 0), (0x07, 4096, 50)]);
+        assert_eq!(partitions_in(&read_in(&disk)).unwrap(), [
+            Partition { start: 2048 * 512, len: 100 * 512 },
+            Partition { start: 4096 * 512, len: 50 * 512 },
+        ]);
+    }
+
+    #[test]
+    fn logical_partitions() {
+        let mut disk = vec![0; 512 * 24];
+        boot_record(&mut disk, 0, &[(0x83, 1, 3), (0x05, 4, 20)]);
+        // every record: its partition, relative to it, then the next record, relative to the extended one
+        boot_record(&mut disk, 512 * 4, &[(0x83, 1, 5), (0x05, 8, 12)]);
+        boot_record(&mut disk, 512 * 12, &[(0x83, 2, 6)]);
+        assert_eq!(partitions_in(&read_in(&disk)).unwrap(), [
+            Partition { start: 512, len: 3 * 512 },
+            Partition { start: 5 * 512, len: 5 * 512 },
+            Partition { start: 14 * 512, len: 6 * 512 },
+        ]);
+    }
+
+    #[test]
+    fn logical_partitions_loop() {
+        let mut disk = vec![0; 512 * 8];
+        boot_record(&mut disk, 0, &[(0x0F, 4, 4)]);
+        // the next record is this one
+        boot_record(&mut disk, 512 * 4, &[(0x83, 1, 1), (0x05, 0, 4)]);
+        assert_eq!(partitions_in(&read_in(&disk)).unwrap().len(), MAX_LOGICAL);
+    }
+
+    #[test]
+    fn gpt_partitions() {
+        for sector in SECTOR_SIZES {
+            let disk = gpt_disk(sector as usize, 128, &[(6, 6), (7, 7)]);
+            assert_eq!(partitions_in(&read_in(&disk)).unwrap(), [
+                Partition { start: 6 * sector, len: sector },
+                Partition { start: 7 * sector, len: sector },
+            ]);
+        }
+        let disk = gpt_disk(512, 256, &[(6, 7)]);
+        assert_eq!(partitions_in(&read_in(&disk)).unwrap(), [Partition { start: 6 * 512, len: 2 * 512 }]);
+    }
+
+    #[test]
+    fn invalid_gpt() {
+        // the protective partition is all that's left
+        let protective = [Partition { start: 512, len: u32::MAX as u64 * 512 }];
+        let mut disk = gpt_disk(512, 128, &[(6, 6)]);
+        disk[512 + 30] ^= 1;
+        assert_eq!(partitions_in(&read_in(&disk)).unwrap(), protective);
+        for entry_len in [0, 48, 200, 8192] {
+            let disk = gpt_disk(512, entry_len, &[]);
+            assert_eq!(partitions_in(&read_in(&disk)).unwrap(), protective, "{entry_len}");
+        }
+        // entries past any disk
+        let mut disk = gpt_disk(512, 128, &[]);
+        let header = &mut disk[512..512 + 92];
+        header[72..80].copy_from_slice(&(u64::MAX / 256).to_le_bytes());
+        header[16..20].fill(0);
+        let crc = crc32fast::hash(header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(partitions_in(&read_in(&disk)).unwrap(), protective);
+    }
+
+    #[test]
+    fn invalid_gpt_entries() {
+        let disk = gpt_disk(4096, 128, &[(6, 6), (u64::MAX / 1024, u64::MAX / 1024), (3, u64::MAX), (7, 5)]);
+        assert_eq!(partitions_in(&read_in(&disk)).unwrap(), [Partition { start: 6 * 4096, len: 4096 }]);
+    }
+}
@@ -0,0 +1,32 @@
+use std::{env, fs::File, io::{self, Read, Write}, path::Path};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+const STASH_ENV: &str = "RMLS_STASH_MAX";
+const STASH_MAX: u64 = 64 * 1024;
+
+/// Files up to this size are copied into the database (compressed) when they're deleted, so
+/// recovering them doesn't depend on the device. In order of preference: `--stash-max <bytes>`,
+/// `$RMLS_STASH_MAX` and 64 KiB. 0 stashes only the empty files.
+pub fn stash_max(flag: Option<u64>) -> u64 {
+    flag.or_else(|| env::var(STASH_ENV).ok()?.trim().parse().ok())
+        .unwrap_or(STASH_MAX)
+}
+
+/// The content of `path`, compressed. It fails if the file isn't `len` bytes long anymore.
+pub(crate) fn stash(path: &Path, len: u64) -> io::Result<Vec<u8>> {
+    let mut content = Vec::with_capacity(len as usize);
+    File::open(path)?.take(len + 1).read_to_end(&mut content)?;
+    if content.len() as u64 != len {
+        return Err(io::Error::other(format!("{:?} changed while deleting it", path)));
+    }
+    let mut encoder = DeflateEncoder::new(vec![], Compression::default());
+    encoder.write_all(&content)?;
+    encoder.finish()
+}
+
+/// The content of a stash.
+pub(crate) fn unstash(stash: &[u8]) -> io::Result<Vec<u8>> {
+    let mut content = vec![];
+    DeflateDecoder::new(stash).read_to_end(&mut content)?;
+    Ok(content)
+}
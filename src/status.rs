@@ -0,0 +1,70 @@
+use std::io;
+use crate::{manifest::chunk_hash, FileKind, Volume, ZombieFile};
+
+// chunks read of every file, spread over all of it
+const SAMPLES: usize = 16;
+
+/// Whether a deleted file can still be recovered, see [`status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Every chunk read has the content it had when deleted.
+    Intact,
+    /// Some of its blocks were reused.
+    Partial { intact: usize, sampled: usize },
+    /// None of the chunks read has the content it had.
+    Lost,
+    /// It was deleted without the hashes of its chunks, there's nothing to compare with.
+    Unknown,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Intact => write!(f, "intact"),
+            Status::Partial { intact, sampled } => write!(f, "partially overwritten ({intact} of {sampled} chunks read are intact)"),
+            Status::Lost => write!(f, "lost"),
+            Status::Unknown => write!(f, "unknown (no block hashes)"),
+        }
+    }
+}
+
+/// Tells whether `file` can still be recovered from `device` without recovering it: a few of its
+/// chunks are read and compared with the [`Manifest`](crate::Manifest) taken when it was deleted.
+/// Stashed files and files still reachable through another name are always intact. Directories
+/// and symlinks have no data, they're intact too.
+pub fn status<A: AsRef<str>>(file: &ZombieFile, device: A) -> io::Result<Status> {
+    if file.kind != FileKind::File || file.stash.is_some() || file.reachable_via().is_some() {
+        return Ok(Status::Intact);
+    }
+    let Some(manifest) = file.manifest.as_ref().filter(|m| m.chunk > 0) else {
+        return Ok(Status::Unknown);
+    };
+    let volume = Volume::open(file, device.as_ref())?;
+    let extents = file.placed_extents();
+    let chunks = manifest.chunks();
+    let sampled: Vec<usize> = match chunks <= SAMPLES {
+        true => (0..chunks).collect(),
+        false => (0..SAMPLES).map(|i| i * (chunks - 1) / (SAMPLES - 1)).collect(),
+    };
+    let mut intact = 0;
+    for &i in &sampled {
+        let start = i as u64 * manifest.chunk;
+        let end = u64::min(start + manifest.chunk, file.len as u64);
+        // holes and unreadable extents are zeros, as when recovering
+        let mut data = vec![0; end.saturating_sub(start) as usize];
+        for (offset, extent) in extents.iter().filter(|(_, e)| e.readable()) {
+            let (from, to) = (u64::max(start, *offset), u64::min(end, offset + extent.len));
+            if from < to {
+                volume.read_exact_at(&mut data[(from - start) as usize..(to - start) as usize], extent.start + from - offset)?;
+            }
+        }
+        if manifest.hash(i) == Some(&chunk_hash(&data)[..]) {
+            intact += 1;
+        }
+    }
+    Ok(match intact {
+        n if n == sampled.len() => Status::Intact,
+        0 => Status::Lost,
+        _ => Status::Partial { intact, sampled: sampled.len() },
+    })
+}
@@ -0,0 +1,89 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io, path::{Path, PathBuf}};
+use crate::{check_device, recover_file, recover_special, recover_symlink, restore_metadata, FileKind, Recovered, ZombieFile};
+
+/// Recreates the directory `files[root]`, with everything that was deleted with it, at `output`.
+/// `files` are all the entries of the database, in the order they were recorded.
+/// Returns how every recovered file was verified. See [`recover_file`] for `intact_only`.
+pub fn recover_tree<A: AsRef<str>, P: AsRef<Path>>(files: &[ZombieFile], root: usize, device: A, output: P, skip_security_xattrs: bool, intact_only: bool) -> io::Result<Vec<(PathBuf, Recovered)>> {
+    let output = output.as_ref();
+    // before creating anything. Without files to read from it, the device can be anything.
+    if needs_device(files, root) {
+        check_device(&files[root], device.as_ref())?;
+    }
+    fs::create_dir(output)?;
+    // where every recovered directory went. A directory is always recorded before its entries.
+    let mut paths: HashMap<u64, PathBuf> = HashMap::from([(files[root].id, output.to_owned())]);
+    let mut dirs = vec![(root, output.to_owned())];
+    // first recovered name of every file with hard links, the others are linked to it
+    let mut linked: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    let mut verified = vec![];
+    for (i, file) in files.iter().enumerate().skip(root + 1) {
+        let Some(dir) = file.parent.and_then(|id| paths.get(&id)) else {
+            continue;
+        };
+        let name = Path::new(&file.name).file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid name: {:?}", file.name)))?;
+        let path = dir.join(name);
+        match file.kind {
+            FileKind::Directory => {
+                fs::create_dir(&path)?;
+                paths.insert(file.id, path.clone());
+                dirs.push((i, path));
+            }
+            FileKind::Symlink => {
+                recover_symlink(file, &path)?;
+                restore_metadata(file, &path, skip_security_xattrs)?;
+            }
+            FileKind::Special => match recover_special(file, &path) {
+                // only root can create device nodes
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    eprintln!("Warning: couldn't recreate the special file {:?} ({e}), run as root to recover it", path);
+                }
+                res => {
+                    res?;
+                    restore_metadata(file, &path, skip_security_xattrs)?;
+                }
+            },
+            FileKind::File => {
+                if let Some(m) = file.metadata.filter(|m| m.nlink > 1) {
+                    if let Some(first) = linked.get(&(m.dev, m.ino)) {
+                        println!("Linking {:?} to {:?}", path, first);
+                        fs::hard_link(first, &path)?;
+                        continue;
+                    }
+                    linked.insert((m.dev, m.ino), path.clone());
+                }
+                println!("Recovering {:?}", path);
+                for warning in file.warnings() {
+                    println!("  Warning: {warning}");
+                }
+                let recovered = recover_file(file, device.as_ref(), File::create(&path)?, intact_only)?;
+                println!("  {recovered}");
+                restore_metadata(file, &path, skip_security_xattrs)?;
+                verified.push((path, recovered));
+            }
+        }
+    }
+    // creating entries changes the times of the directory and its mode could forbid it,
+    // so the directories go last, the innermost first.
+    for (i, path) in dirs.into_iter().rev() {
+        restore_metadata(&files[i], &path, skip_security_xattrs)?;
+    }
+    Ok(verified)
+}
+
+/// Whether recovering `files[root]` reads from the device: it's a file (or a directory with
+/// files in it) whose content isn't stashed in the database.
+pub fn needs_device(files: &[ZombieFile], root: usize) -> bool {
+    let mut dirs = HashSet::from([files[root].id]);
+    let reads = |file: &ZombieFile| file.kind == FileKind::File && file.stash.is_none();
+    reads(&files[root]) || files.iter().skip(root + 1).any(|file| match file.parent {
+        Some(id) if dirs.contains(&id) => {
+            if file.kind == FileKind::Directory {
+                dirs.insert(file.id);
+            }
+            reads(file)
+        }
+        _ => false,
+    })
+}
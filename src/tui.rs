@@ -16,7 +16,7 @@ pub fn init_panic_hook() {
     set_hook(Box::new(move |panic_info| {
         disable_raw_mode().unwrap();
         stdout().execute(LeaveAlternateScreen).unwrap();
-        println!("{}", panic_info.to_string());
+        println!("{}", panic_info);
     }));
 }
 
@@ -53,7 +53,7 @@ pub fn tui(dir_name: String) -> io::Result<Vec<PathBuf>> {
                 };
                 let name = dir.name.to_str().unwrap_or("Non UTF-8 name");
                 let mut text = Text::raw(format!("{padding}{arrow} {name}"));
-                let current = i == u16::min(state.y as u16, screen.height-2);
+                let current = i == u16::min(state.y, screen.height-2);
                 if current {
                     text = Text::raw(format!("{padding}{arrow} {name}"))
                         .fg(Color::Black)
@@ -168,7 +168,7 @@ impl DirEntry for Entry {
                 return None;
             }
         }
-        return Some(());
+        Some(())
     }
     fn detach(root: &Self) {
         root.borrow_mut().deleted = true;
@@ -208,7 +208,7 @@ impl DirEntry for Entry {
             };
             root = ith_child.clone();
         }
-        return Some(root);
+        Some(root)
     }
     fn entries(root: &Self, visibiliy: EntryState) -> Vec<Entry> {
         let mut acc = vec![];//not show root.
@@ -234,14 +234,14 @@ impl DirEntry for Entry {
                 }
             }
         }
-        return acc;
+        acc
     }
 
     fn insert_node(root: &Entry, child: Self) {
         let mut root_mut = root.borrow_mut();
         let children = root_mut.cached_children.get_or_insert(vec![]);
         children.push(child.clone());
-        child.borrow_mut().parent = Some(Rc::downgrade(&root));
+        child.borrow_mut().parent = Some(Rc::downgrade(root));
     }
 
     fn next(root: &Entry) -> Option<Entry> {
@@ -289,12 +289,12 @@ impl DirEntry for Entry {
                     .find(|x| !x.borrow().deleted)
                     .cloned();
                 //we found a child to the left of ourselves.
-                if child.is_some() {
+                if let Some(child) = child {
                     //println!("PREVIOUS: {:?}", child);
-                    break child.unwrap();
+                    break child;
                 }
                 //we didn't but if the parent is valid, we return it
-                if !parent_.deleted && parent_.id != &[0] {
+                if !parent_.deleted && parent_.id != [0] {
                     return Some(parent.clone());
                 }
                 take = *parent_.id.last()?;
@@ -321,7 +321,7 @@ impl DirEntry for Entry {
                 }
             };
         }
-        return Some(new_root);
+        Some(new_root)
     }
 
     fn request_children(root: &Self){
@@ -331,8 +331,7 @@ impl DirEntry for Entry {
         let mut children = vec![];
         let new_path = Path::new(&root.borrow().path).join(&root.borrow().name);
         let entries = std::fs::read_dir(&new_path)
-            .expect("Error opening dir")
-            .into_iter();
+            .expect("Error opening dir");
         for (i, entry) in entries.enumerate() {
             let path = entry.unwrap().path();
             let is_file = path.is_file();
@@ -430,7 +429,7 @@ fn delete(app: &mut State, from: &[usize], to: &[usize]) {
             } else {
                 panic!("ALL FILES DELETED");
             }
-            if &current.borrow().id == &to {
+            if current.borrow().id == to {
                 app.deletions.push((acc, y, skip));
                 break;
             }
@@ -494,14 +493,12 @@ fn handle_events(app: &mut State, height: u16) -> io::Result<()> {
                         }
                     }
                     KeyCode::Esc => app.selecting = None,
-                    KeyCode::Char(' ') => {
-                        if app.selecting.is_none() {
-                            app.enter = !app.enter;
-                            let Some(current) = DirEntry::get(&app.root, &app.id) else { panic!("root: {:?}\nID: {:?}", app.root, app.id) };
-                            if !current.borrow().is_file {
-                                current.borrow_mut().open ^= true; // so that assignment is not that long :)
-                                DirEntry::request_children(&current);
-                            }
+                    KeyCode::Char(' ') if app.selecting.is_none() => {
+                        app.enter = !app.enter;
+                        let Some(current) = DirEntry::get(&app.root, &app.id) else { panic!("root: {:?}\nID: {:?}", app.root, app.id) };
+                        if !current.borrow().is_file {
+                            current.borrow_mut().open ^= true; // so that assignment is not that long :)
+                            DirEntry::request_children(&current);
                         }
                     }
                     _ => {} // avoiding rest of characters
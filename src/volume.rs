@@ -0,0 +1,365 @@
+use std::{cell::RefCell, collections::HashMap, fs::{self, File}, io, os::unix::fs::{FileExt, FileTypeExt, MetadataExt}, path::Path, process::Command};
+use crate::{btrfs::ChunkMap, device::{format_dev, open_device, uuid_in}, loopdev::image_extents, partition::partitions, Extent, ExtentFlags, ZombieFile};
+
+const SECTOR: u64 = 512;
+
+/// The filesystem a deleted file was in, read through the device given to recover it. That
+/// device doesn't have to be the filesystem itself: it can be one under it (the physical volume
+/// of an LVM volume, for instance), its addresses are translated. On btrfs the addresses of
+/// extents are logical ones, they're translated through its chunk tree too.
+pub struct Volume {
+    device: File,
+    map: Map,
+    // other devices the filesystem is in, striped over several of them, by major:minor
+    others: RefCell<HashMap<String, File>>,
+    btrfs: Option<ChunkMap>,
+}
+
+/// Reads the bytes of a filesystem at offsets in it, what its free space is looked up through: a
+/// [`Volume`], or an image in memory in tests.
+pub(crate) type ReadAt = Box<dyn Fn(&mut [u8], u64) -> io::Result<()>>;
+
+// where every byte of the filesystem is in the device
+enum Map {
+    /// The filesystem is the `len` bytes at `start`: all the device, or one of its partitions.
+    Offset { start: u64, len: u64 },
+    /// Device-mapper devices between the filesystem (`top`) and the device (`bottom`). Their
+    /// tables are read when the volume is opened, the dm devices have to exist.
+    Dm { top: String, bottom: String, tables: HashMap<String, Vec<Target>> },
+    /// The filesystem is at `offset` of an image file (of a loop device), with these extents in
+    /// the device: `(offset in the image, Extent)`, in order.
+    Image { offset: u64, extents: Vec<(u64, Extent)> },
+}
+
+// a line of a device-mapper table, in bytes
+#[derive(Debug, Clone)]
+struct Target {
+    start: u64,
+    len: u64,
+    kind: TargetKind,
+}
+
+#[derive(Debug, Clone)]
+enum TargetKind {
+    Linear { dev: String, offset: u64 },
+    Striped { chunk: u64, stripes: Vec<(String, u64)> },
+    /// Anything else (crypt, thin, raid...), its data isn't in the devices under it as is.
+    Unsupported(String),
+}
+
+impl Volume {
+    /// Opens `device` to read the filesystem `file` was in, and checks it's the right one: the
+    /// UUID in its superblock has to be the one recorded. Files recorded without it can't be
+    /// checked, `device` is taken as the filesystem itself.
+    pub fn open(file: &ZombieFile, device: &str) -> io::Result<Volume> {
+        let mut volume = Volume::find(file, device)?;
+        volume.btrfs = ChunkMap::read(|buf, pos| volume.read_device(buf, pos))?;
+        let compressed = file.extents.iter().filter(|e| e.flags().contains(ExtentFlags::ENCODED)).count();
+        if volume.btrfs.is_some() && compressed > 0 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported,
+                format!("{:?} has {compressed} extent(s) compressed by btrfs: they're compressed on the disk too, and rmls can't decompress them", file.name)));
+        }
+        Ok(volume)
+    }
+
+    // the filesystem `file` was in, in `device` or under it
+    fn find(file: &ZombieFile, device: &str) -> io::Result<Volume> {
+        let opened = open_device(device)?;
+        let with = |map| Ok::<_, io::Error>(Volume { device: opened.try_clone()?, map, others: RefCell::default(), btrfs: None });
+        let at = |start, len| with(Map::Offset { start, len });
+        let direct = at(0, u64::MAX)?;
+        let Some(expected) = &file.fs_uuid else {
+            return Ok(direct);
+        };
+        let found = direct.uuid()?;
+        if found.as_ref().is_some_and(|uuid| uuid.eq_ignore_ascii_case(expected)) {
+            return Ok(direct);
+        }
+        // a whole disk (or an image of one), the filesystem could be in one of its partitions
+        let partitions = partitions(&opened)?;
+        for partition in &partitions {
+            let volume = at(partition.start, partition.len)?;
+            if volume.uuid()?.is_some_and(|uuid| uuid.eq_ignore_ascii_case(expected)) {
+                return Ok(volume);
+            }
+        }
+        // the filesystem was in a loop device: this is its image file, or the device the image is in
+        if let Some(backing) = &file.loop_backing {
+            let metadata = opened.metadata()?;
+            let map = match metadata.file_type() {
+                t if t.is_file() => Some(Map::Offset { start: backing.offset, len: u64::MAX }),
+                t if t.is_block_device() && fs::metadata(&backing.file).is_ok_and(|m| m.dev() == metadata.rdev()) => {
+                    Some(Map::Image { offset: backing.offset, extents: image_extents(backing)? })
+                }
+                _ => None,
+            };
+            if let Some(volume) = map.map(with).transpose()? {
+                if volume.uuid()?.is_some_and(|uuid| uuid.eq_ignore_ascii_case(expected)) {
+                    return Ok(volume);
+                }
+            }
+        }
+        // the filesystem could be in a device-mapper device over this one
+        if let Some(map) = dm_map(file, &opened)? {
+            let volume = Volume { device: opened, map, others: RefCell::default(), btrfs: None };
+            return match volume.uuid()? {
+                Some(uuid) if uuid.eq_ignore_ascii_case(expected) => Ok(volume),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("{:?} is under the device of {:?}, but the filesystem there isn't the one it was in (UUID {expected})", device, file.name))),
+            };
+        }
+        Err(match found {
+            _ if !partitions.is_empty() => io::Error::new(io::ErrorKind::InvalidInput,
+                format!("None of the {} partitions of {:?} is the filesystem {:?} was in (UUID {expected})", partitions.len(), device, file.name)),
+            Some(uuid) => io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{:?} is not the filesystem {:?} was in: its UUID is {uuid}, it should be {expected}", device, file.name)),
+            None => io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Couldn't check that {:?} is the filesystem {:?} was in (UUID {expected}): unknown filesystem", device, file.name)),
+        })
+    }
+
+    /// Whether the filesystem is btrfs, with logical addresses.
+    pub(crate) fn is_btrfs(&self) -> bool {
+        self.btrfs.is_some()
+    }
+
+    fn uuid(&self) -> io::Result<Option<String>> {
+        uuid_in(|buf, pos| self.read_device(buf, pos))
+    }
+
+    /// Reads `buf.len()` bytes at the address `pos` of the filesystem, as FIEMAP gives them.
+    pub fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        match &self.btrfs {
+            Some(chunks) => chunks.read_exact_at(|buf, pos| self.read_device(buf, pos), buf, pos),
+            None => self.read_device(buf, pos),
+        }
+    }
+
+    /// Reads at the offset `pos` of the device of the filesystem (where its superblock is, and
+    /// its bitmaps or btrees).
+    pub(crate) fn into_read_at(self) -> ReadAt {
+        Box::new(move |buf, pos| self.read_device(buf, pos))
+    }
+
+    pub(crate) fn read_device(&self, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            let (dev, at, len) = self.map.locate(pos)?;
+            let n = u64::min(len, buf.len() as u64) as usize;
+            match dev {
+                None => self.device.read_exact_at(&mut buf[..n], at)?,
+                Some(dev) => {
+                    let mut others = self.others.borrow_mut();
+                    let other = match others.get(dev) {
+                        Some(other) => other,
+                        None => {
+                            let path = Path::new("/dev/block").join(dev);
+                            let other = File::open(&path)
+                                .map_err(|e| io::Error::new(e.kind(), format!("Error opening device {:?}, part of the filesystem is there: {e}", path)))?;
+                            others.entry(dev.to_owned()).or_insert(other)
+                        }
+                    };
+                    other.read_exact_at(&mut buf[..n], at)?;
+                }
+            }
+            buf = &mut buf[n..];
+            pos += n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Map {
+    // where the byte `pos` of the filesystem is: in which device (None for the one given), at
+    // which address, and how many bytes after it follow it there
+    fn locate(&self, pos: u64) -> io::Result<(Option<&str>, u64, u64)> {
+        let (top, bottom, tables) = match self {
+            Map::Image { offset, extents } => {
+                let pos = offset + pos;
+                let i = extents.partition_point(|(logical, _)| *logical <= pos);
+                return match i.checked_sub(1).map(|i| &extents[i]) {
+                    Some((logical, extent)) if pos < logical + extent.len && extent.readable() => {
+                        Ok((None, extent.start + pos - logical, logical + extent.len - pos))
+                    }
+                    Some((logical, extent)) if pos < logical + extent.len => Err(io::Error::new(io::ErrorKind::Unsupported,
+                        format!("Byte {pos} of the image file can't be read from its device, pass the image file"))),
+                    _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Byte {pos} of the image file is in a hole or past its end"))),
+                };
+            }
+            Map::Offset { len, .. } if pos >= *len => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Byte {pos} is beyond the end of the filesystem")));
+            }
+            Map::Offset { start, len } => return Ok((None, start + pos, len - pos)),
+            Map::Dm { top, bottom, tables } => (top, bottom, tables),
+        };
+        let (mut dev, mut pos, mut len) = (top, pos, u64::MAX);
+        // down to a device that isn't a device-mapper one
+        while dev != bottom && tables.contains_key(dev) {
+            let beyond = || io::Error::new(io::ErrorKind::UnexpectedEof, format!("Byte {pos} is beyond the end of device {dev}"));
+            let target = tables[dev].iter().find(|t| t.start <= pos && pos < t.start + t.len).ok_or_else(beyond)?;
+            let relative = pos - target.start;
+            len = u64::min(len, target.len - relative);
+            (dev, pos) = match &target.kind {
+                TargetKind::Linear { dev, offset } => (dev, offset + relative),
+                TargetKind::Striped { chunk, stripes } => {
+                    let n = relative / chunk;
+                    let (dev, offset) = &stripes[(n % stripes.len() as u64) as usize];
+                    len = u64::min(len, chunk - relative % chunk);
+                    (dev, offset + n / stripes.len() as u64 * chunk + relative % chunk)
+                }
+                TargetKind::Unsupported(kind) => return Err(unsupported(kind, dev)),
+            };
+        }
+        Ok(((dev != bottom).then_some(dev.as_str()), pos, len))
+    }
+}
+
+fn unsupported(kind: &str, dev: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported,
+        format!("The data of device-mapper target {kind:?} (device {dev}) can't be read from the devices under it, pass the device of the filesystem"))
+}
+
+// The tables from the device of the filesystem `file` was in, if it's a device-mapper device,
+// down to `device`. None if neither of them is a device-mapper device or `device` isn't under it.
+fn dm_map(file: &ZombieFile, device: &File) -> io::Result<Option<Map>> {
+    let (Some(metadata), true) = (file.metadata, device.metadata()?.file_type().is_block_device()) else {
+        return Ok(None);
+    };
+    let top = format_dev(metadata.dev);
+    let bottom = format_dev(device.metadata()?.rdev());
+    let mut tables = HashMap::new();
+    let mut pending = vec![top.clone()];
+    let mut reached = false;
+    // a target that can't be translated, it could be the reason `device` isn't reached
+    let mut unsupported_target = None;
+    while let Some(dev) = pending.pop() {
+        if dev == bottom {
+            reached = true;
+            continue;
+        }
+        if tables.contains_key(&dev) {
+            continue;
+        }
+        let Some(table) = dm_table(&dev)? else {
+            continue;
+        };
+        for target in &table {
+            match &target.kind {
+                TargetKind::Linear { dev, .. } => pending.push(dev.clone()),
+                TargetKind::Striped { stripes, .. } => pending.extend(stripes.iter().map(|(dev, _)| dev.clone())),
+                TargetKind::Unsupported(kind) => unsupported_target = Some((dev.clone(), kind.clone())),
+            }
+        }
+        tables.insert(dev, table);
+    }
+    match (reached, unsupported_target) {
+        (true, _) => Ok(Some(Map::Dm { top, bottom, tables })),
+        (false, Some((dev, kind))) => Err(unsupported(&kind, &dev)),
+        (false, None) => Ok(None),
+    }
+}
+
+// Table of the device-mapper device `dev` (major:minor), None if it isn't one.
+fn dm_table(dev: &str) -> io::Result<Option<Vec<Target>>> {
+    let sys = Path::new("/sys/dev/block").join(dev).join("dm");
+    let Ok(name) = fs::read_to_string(sys.join("name")) else {
+        return Ok(None);
+    };
+    let output = Command::new("dmsetup").args(["table", name.trim()]).output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Couldn't run dmsetup to read the table of {}: {e}", name.trim())))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("dmsetup table {}: {}", name.trim(), String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    String::from_utf8_lossy(&output.stdout).lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_target)
+        .collect::<io::Result<_>>()
+        .map(Some)
+}
+
+// <start> <length> <type> <arguments...>, in sectors. Devices are major:minor.
+fn parse_target(line: &str) -> io::Result<Target> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid device-mapper table line: {line:?}"));
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let number = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok()).ok_or_else(invalid);
+    let kind = match *fields.get(2).ok_or_else(invalid)? {
+        "linear" => TargetKind::Linear { dev: fields.get(3).ok_or_else(invalid)?.to_string(), offset: number(4)? * SECTOR },
+        "striped" => {
+            let n = number(3)? as usize;
+            let stripes = (0..n)
+                .map(|i| Ok((fields.get(5 + 2 * i).ok_or_else(invalid)?.to_string(), number(6 + 2 * i)? * SECTOR)))
+                .collect::<io::Result<Vec<_>>>()?;
+            match (number(4)? * SECTOR, stripes.is_empty()) {
+                (0, _) | (_, true) => return Err(invalid()),
+                (chunk, false) => TargetKind::Striped { chunk, stripes },
+            }
+        }
+        other => TargetKind::Unsupported(other.to_owned()),
+    };
+    Ok(Target { start: number(0)? * SECTOR, len: number(1)? * SECTOR, kind })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_linear() {
+        let target = parse_target("0 2048 linear 8:1 4096").unwrap();
+        assert_eq!((target.start, target.len), (0, 2048 * SECTOR));
+        assert!(matches!(target.kind, TargetKind::Linear { ref dev, offset } if dev == "8:1" && offset == 4096 * SECTOR));
+    }
+
+    #[test]
+    fn parses_striped() {
+        let target = parse_target("2048 4096 striped 2 128 8:16 0 8:32 2048").unwrap();
+        assert_eq!((target.start, target.len), (2048 * SECTOR, 4096 * SECTOR));
+        let TargetKind::Striped { chunk, stripes } = target.kind else {
+            panic!("not striped: {:?}", target.kind);
+        };
+        assert_eq!(chunk, 128 * SECTOR);
+        assert_eq!(stripes, [("8:16".to_owned(), 0), ("8:32".to_owned(), 2048 * SECTOR)]);
+    }
+
+    #[test]
+    fn keeps_other_targets() {
+        let target = parse_target("0 1024 crypt aes-xts-plain64 :64:logon:cryptsetup 0 8:2 4096").unwrap();
+        assert!(matches!(target.kind, TargetKind::Unsupported(ref kind) if kind == "crypt"));
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        for line in ["0 2048", "0 x linear 8:1 0", "0 2048 linear 8:1", "0 2048 striped 2 128 8:16 0", "0 2048 striped 0 128", "0 2048 striped 1 0 8:16 0"] {
+            assert_eq!(parse_target(line).unwrap_err().kind(), io::ErrorKind::InvalidData, "{line}");
+        }
+    }
+
+    #[test]
+    fn locates_through_stripes() {
+        // top (253:1) is striped over 253:0 and 8:16 in chunks of 8 sectors, 253:0 is linear in 8:16
+        let tables = HashMap::from([
+            ("253:1".to_owned(), vec![parse_target("0 64 striped 2 8 253:0 0 8:16 1000").unwrap()]),
+            ("253:0".to_owned(), vec![parse_target("0 32 linear 8:16 100").unwrap()]),
+        ]);
+        let map = Map::Dm { top: "253:1".to_owned(), bottom: "8:16".to_owned(), tables };
+        // first chunk, in 253:0 then 8:16
+        assert_eq!(map.locate(3 * SECTOR).unwrap(), (None, 103 * SECTOR, 5 * SECTOR));
+        // second chunk, directly in 8:16
+        assert_eq!(map.locate(9 * SECTOR).unwrap(), (None, 1001 * SECTOR, 7 * SECTOR));
+        // third chunk, the second one of the first stripe
+        assert_eq!(map.locate(16 * SECTOR).unwrap(), (None, 108 * SECTOR, 8 * SECTOR));
+        assert_eq!(map.locate(64 * SECTOR).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn locates_in_other_devices() {
+        let tables = HashMap::from([("253:0".to_owned(), vec![
+            parse_target("0 16 linear 8:16 0").unwrap(),
+            parse_target("16 16 linear 8:32 0").unwrap(),
+            parse_target("32 16 crypt aes 0 8:48 0").unwrap(),
+        ])]);
+        let map = Map::Dm { top: "253:0".to_owned(), bottom: "8:16".to_owned(), tables };
+        assert_eq!(map.locate(SECTOR).unwrap(), (None, SECTOR, 15 * SECTOR));
+        assert_eq!(map.locate(20 * SECTOR).unwrap(), (Some("8:32"), 4 * SECTOR, 12 * SECTOR));
+        assert_eq!(map.locate(40 * SECTOR).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+}
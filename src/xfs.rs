@@ -0,0 +1,256 @@
+use std::{collections::HashMap, io};
+use crate::{bytes::{be_u16, be_u32, be_u64}, volume::ReadAt, Allocation, Volume};
+
+const SUPERBLOCK_LEN: usize = 512;
+const MAGIC: &[u8; 4] = b"XFSB";
+const AGF_MAGIC: &[u8; 4] = b"XAGF";
+// by-block free space btrees, without and with CRCs (version 5)
+const BNO_MAGIC: &[u8; 4] = b"ABTB";
+const BNO_CRC_MAGIC: &[u8; 4] = b"AB3B";
+const BLOCK_HEADER_LEN: usize = 16;
+const BLOCK_CRC_HEADER_LEN: usize = 56;
+// a record of a leaf (and a key of a node): start and length of a free extent, in AG blocks
+const RECORD_LEN: usize = 8;
+const PTR_LEN: usize = 4;
+// deeper than any real btree, a corrupted one could loop
+const MAX_LEVEL: u16 = 16;
+
+/// UUID in the superblock of an XFS filesystem, from its first bytes. None if it isn't one.
+pub(crate) fn superblock_uuid(start: &[u8]) -> Option<[u8; 16]> {
+    let sb = start.get(..SUPERBLOCK_LEN)?;
+    (&sb[..4] == MAGIC).then(|| sb[32..48].try_into().unwrap_or_default())
+}
+
+/// An XFS filesystem, read through its volume, to look up blocks in the free space btrees of its
+/// allocation groups (AGs).
+pub struct Xfs {
+    read: ReadAt,
+    block_size: u64,
+    sector_size: u64,
+    blocks_count: u64,
+    ag_blocks: u64,
+    ag_count: u64,
+    crc: bool,
+    uuid: [u8; 16],
+    // free extents of the AGs read so far, (start, length) in blocks of the AG, in order
+    free: HashMap<u64, Vec<(u64, u64)>>,
+}
+
+impl Xfs {
+    /// Reads the superblock of `volume`, fails if it isn't XFS.
+    pub fn open(volume: Volume) -> io::Result<Self> {
+        Self::read_from(volume.into_read_at())
+    }
+
+    fn read_from(read: ReadAt) -> io::Result<Self> {
+        let mut sb = [0; SUPERBLOCK_LEN];
+        read(&mut sb, 0)?;
+        if &sb[..4] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an XFS filesystem"));
+        }
+        let (block_size, sector_size) = (be_u32(&sb, 4) as u64, be_u16(&sb, 102) as u64);
+        if !(512..=65536).contains(&block_size) || !block_size.is_power_of_two() || !(512..=block_size).contains(&sector_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid XFS block size"));
+        }
+        let (ag_blocks, ag_count) = (be_u32(&sb, 84) as u64, be_u32(&sb, 88) as u64);
+        if ag_blocks == 0 || ag_count == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid XFS allocation groups"));
+        }
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&sb[32..48]);
+        Ok(Xfs {
+            read,
+            block_size,
+            sector_size,
+            blocks_count: be_u64(&sb, 8),
+            ag_blocks,
+            ag_count,
+            crc: be_u16(&sb, 100) & 0xF == 5,
+            uuid,
+            free: HashMap::new(),
+        })
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    fn read_block(&self, ag: u64, block: u64) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; self.block_size as usize];
+        (self.read)(&mut buf, (ag * self.ag_blocks + block) * self.block_size)?;
+        Ok(buf)
+    }
+
+    // The free extents of `ag`, from the leaves of its by-block free space btree. Its root is in
+    // the AG free space header (AGF), in the second sector of the AG.
+    fn free_extents(&mut self, ag: u64) -> io::Result<&[(u64, u64)]> {
+        if !self.free.contains_key(&ag) {
+            let invalid = |what: String| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid XFS allocation group {ag}: {what}"));
+            let mut agf = vec![0; self.sector_size as usize];
+            (self.read)(&mut agf, ag * self.ag_blocks * self.block_size + self.sector_size)?;
+            if &agf[..4] != AGF_MAGIC || be_u32(&agf, 8) as u64 != ag {
+                return Err(invalid("no free space header".to_owned()));
+            }
+            let (magic, header_len) = match self.crc {
+                true => (BNO_CRC_MAGIC, BLOCK_CRC_HEADER_LEN),
+                false => (BNO_MAGIC, BLOCK_HEADER_LEN),
+            };
+            let root_level = be_u32(&agf, 28).checked_sub(1).filter(|&l| l < MAX_LEVEL as u32)
+                .ok_or_else(|| invalid("free space btree too deep".to_owned()))? as u16;
+            let mut free = vec![];
+            let mut pending = vec![(be_u32(&agf, 16) as u64, root_level)];
+            while let Some((block, level)) = pending.pop() {
+                if block >= self.ag_blocks {
+                    return Err(invalid(format!("free space btree block {block} outside of it")));
+                }
+                let node = self.read_block(ag, block)?;
+                let records = be_u16(&node, 6) as usize;
+                if &node[..4] != magic || be_u16(&node, 4) != level {
+                    return Err(invalid(format!("block {block} isn't one of its free space btree")));
+                }
+                match level {
+                    0 => {
+                        let leaf = node.get(header_len..header_len + records * RECORD_LEN)
+                            .ok_or_else(|| invalid(format!("too many records in block {block}")))?;
+                        free.extend(leaf.chunks(RECORD_LEN).map(|r| (be_u32(r, 0) as u64, be_u32(r, 4) as u64)));
+                    }
+                    _ => {
+                        // keys, then pointers, both as many as fit
+                        let ptrs = header_len + (node.len() - header_len) / (RECORD_LEN + PTR_LEN) * RECORD_LEN;
+                        let ptrs = node.get(ptrs..ptrs + records * PTR_LEN)
+                            .ok_or_else(|| invalid(format!("too many pointers in block {block}")))?;
+                        pending.extend(ptrs.chunks(PTR_LEN).map(|p| (be_u32(p, 0) as u64, level - 1)));
+                    }
+                }
+            }
+            free.sort_unstable();
+            self.free.insert(ag, free);
+        }
+        Ok(&self.free[&ag])
+    }
+
+    // how many of the blocks `first..last` of `ag` are free
+    fn free_blocks(&mut self, ag: u64, first: u64, last: u64) -> io::Result<u64> {
+        let free = self.free_extents(ag)?;
+        // the one before the first starting in the range could reach into it
+        let i = free.partition_point(|(start, _)| *start <= first).saturating_sub(1);
+        Ok(free[i..].iter()
+            .take_while(|(start, _)| *start < last)
+            .map(|(start, len)| u64::min(last, start + len).saturating_sub(u64::max(first, *start)))
+            .sum())
+    }
+
+    /// Whether the blocks `first..last` are allocated now. FIEMAP gives addresses in the device,
+    /// AGs follow one another there.
+    pub(crate) fn allocation(&mut self, first: u64, last: u64) -> io::Result<Allocation> {
+        if last > self.blocks_count || last > self.ag_count * self.ag_blocks {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Extent outside of the filesystem, wrong device?"));
+        }
+        let mut free = 0;
+        let mut block = first;
+        while block < last {
+            let (ag, start) = (block / self.ag_blocks, block % self.ag_blocks);
+            let end = u64::min(self.ag_blocks, start + last - block);
+            free += self.free_blocks(ag, start, end)?;
+            block += end - start;
+        }
+        Ok(match free {
+            0 => Allocation::Allocated,
+            n if n == last - first => Allocation::Free,
+            _ => Allocation::Partial,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 512;
+    const AG_BLOCKS: usize = 64;
+
+    fn read_in(disk: Vec<u8>) -> ReadAt {
+        Box::new(move |buf, pos| {
+            let bytes = disk.get(pos as usize..pos as usize + buf.len()).ok_or(io::ErrorKind::UnexpectedEof)?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        })
+    }
+
+    fn set(b: &mut [u8], at: usize, value: u32) {
+        b[at..at + 4].copy_from_slice(&value.to_be_bytes());
+    }
+
+    // a btree block at `block` of `ag`: its records (start, length) in a leaf, its pointers in a node
+    fn btree_block(disk: &mut [u8], crc: bool, ag: usize, block: usize, level: u16, entries: &[(u32, u32)]) {
+        let (magic, header_len) = match crc {
+            true => (BNO_CRC_MAGIC, BLOCK_CRC_HEADER_LEN),
+            false => (BNO_MAGIC, BLOCK_HEADER_LEN),
+        };
+        let b = &mut disk[(ag * AG_BLOCKS + block) * BLOCK..][..BLOCK];
+        b[..4].copy_from_slice(magic);
+        b[4..6].copy_from_slice(&level.to_be_bytes());
+        b[6..8].copy_from_slice(&(entries.len() as u16).to_be_bytes());
+        let ptrs = header_len + (BLOCK - header_len) / (RECORD_LEN + PTR_LEN) * RECORD_LEN;
+        for (i, &(start, len)) in entries.iter().enumerate() {
+            set(b, header_len + i * RECORD_LEN, start);
+            match level {
+                0 => set(b, header_len + i * RECORD_LEN + 4, len),
+                // the key is the first record under the pointer, `len` is where it is
+                _ => set(b, ptrs + i * PTR_LEN, len),
+            }
+        }
+    }
+
+    // two AGs: the first one with a btree of two levels, the second all free
+    fn image(crc: bool) -> Vec<u8> {
+        let mut disk = vec![0; 2 * AG_BLOCKS * BLOCK];
+        disk[..4].copy_from_slice(MAGIC);
+        set(&mut disk, 4, BLOCK as u32);
+        disk[8..16].copy_from_slice(&(2 * AG_BLOCKS as u64).to_be_bytes());
+        set(&mut disk, 84, AG_BLOCKS as u32);
+        set(&mut disk, 88, 2);
+        disk[100..102].copy_from_slice(&(if crc { 5u16 } else { 4 }).to_be_bytes());
+        disk[102..104].copy_from_slice(&(BLOCK as u16).to_be_bytes());
+        for (ag, root, levels) in [(0, 4, 2), (1, 4, 1)] {
+            let agf = &mut disk[ag * AG_BLOCKS * BLOCK + BLOCK..];
+            agf[..4].copy_from_slice(AGF_MAGIC);
+            set(agf, 8, ag as u32);
+            set(agf, 16, root);
+            set(agf, 28, levels);
+        }
+        btree_block(&mut disk, crc, 0, 4, 1, &[(10, 5), (40, 6)]);
+        btree_block(&mut disk, crc, 0, 5, 0, &[(10, 5), (20, 10)]);
+        btree_block(&mut disk, crc, 0, 6, 0, &[(40, 24)]);
+        btree_block(&mut disk, crc, 1, 4, 0, &[(0, 64)]);
+        disk
+    }
+
+    #[test]
+    fn free_space_btrees() {
+        for crc in [false, true] {
+            let mut xfs = Xfs::read_from(read_in(image(crc))).unwrap();
+            assert_eq!(xfs.free_extents(0).unwrap(), [(10, 5), (20, 10), (40, 24)], "crc: {crc}");
+            assert_eq!(xfs.free_extents(1).unwrap(), [(0, 64)], "crc: {crc}");
+            assert_eq!(xfs.allocation(10, 15).unwrap(), Allocation::Free);
+            assert_eq!(xfs.allocation(0, 10).unwrap(), Allocation::Allocated);
+            assert_eq!(xfs.allocation(12, 22).unwrap(), Allocation::Partial);
+            // across the AGs
+            assert_eq!(xfs.allocation(50, 80).unwrap(), Allocation::Free);
+            assert_eq!(xfs.allocation(100, 129).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn blocks_of_another_btree() {
+        // the blocks of a filesystem with CRCs, read as one without them
+        let mut disk = image(true);
+        disk[100..102].copy_from_slice(&4u16.to_be_bytes());
+        let mut xfs = Xfs::read_from(read_in(disk)).unwrap();
+        assert_eq!(xfs.free_extents(0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}